@@ -0,0 +1,91 @@
+use crate::one_bit_llm::parts::Layer;
+
+// The L2 norm of every accumulated gradient in `layer` taken together, as if flattened into one vector.
+pub fn global_gradient_norm(layer: &dyn Layer) -> f32 {
+    let mut total: f32 = 0.0;
+
+    for (_, parameter) in layer.parameters() {
+        if let Some(gradient) = parameter.borrow().gradient.as_ref() {
+            total += gradient.data.iter().map(|value| value * value).sum::<f32>();
+        }
+    }
+
+    total.sqrt()
+}
+
+// Rescales every gradient so the global norm is at most `max_norm`, matching `tf.clip_by_global_norm` in the
+// Python trainer. Scaling all gradients together keeps the update direction. Returns the norm before clipping.
+pub fn clip_gradients_by_global_norm(layer: &mut dyn Layer, max_norm: f32) -> f32 {
+    let norm: f32 = global_gradient_norm(layer);
+
+    if norm > max_norm {
+        let scale: f32 = max_norm / norm;
+
+        for (_, parameter) in layer.parameters_mut() {
+            let mut data = parameter.borrow_mut();
+
+            if let Some(gradient) = data.gradient.take() {
+                data.gradient = Some(gradient * scale);
+            }
+        }
+    }
+
+    norm
+}
+
+// Clamps every gradient value into [-max_value, max_value], like `tf.clip_by_value`.
+pub fn clip_gradients_by_value(layer: &mut dyn Layer, max_value: f32) {
+    for (_, parameter) in layer.parameters_mut() {
+        if let Some(gradient) = parameter.borrow_mut().gradient.as_mut() {
+            for value in gradient.data.iter_mut() {
+                *value = value.clamp(-max_value, max_value);
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::algorithms::fixtures::dense_with_gradients;
+    use crate::matrix::matrix::Matrix;
+    use crate::one_bit_llm::parts::Dense;
+
+    // Weight gradients of norm 5 and bias gradients of norm 12, so the global norm is 13.
+    fn layer_with_gradients() -> Dense {
+        dense_with_gradients(1, 2, &[3.0, -4.0], &[0.0, 12.0])
+    }
+    #[test]
+    fn global_norm_clipping_scales_the_norm_to_the_maximum() {
+        let mut layer: Dense = layer_with_gradients();
+
+        assert!((global_gradient_norm(&layer) - 13.0).abs() < 1e-5);
+        assert!((clip_gradients_by_global_norm(&mut layer, 1.0) - 13.0).abs() < 1e-5);
+        assert!((global_gradient_norm(&layer) - 1.0).abs() < 1e-6);
+
+        // Every gradient is scaled by the same factor, so the direction is unchanged.
+        let weight_gradient: Matrix<f32> = layer.weights.borrow().gradient.clone().unwrap();
+        assert!((weight_gradient.data[0] - 3.0 / 13.0).abs() < 1e-6);
+        assert!((weight_gradient.data[1] + 4.0 / 13.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn global_norm_clipping_leaves_small_gradients_alone() {
+        let mut layer: Dense = layer_with_gradients();
+
+        clip_gradients_by_global_norm(&mut layer, 20.0);
+
+        assert_eq!(layer.biases.borrow().gradient.clone().unwrap().data, vec![0.0, 12.0]);
+    }
+
+    #[test]
+    fn value_clipping_clamps_each_gradient() {
+        let mut layer: Dense = layer_with_gradients();
+
+        clip_gradients_by_value(&mut layer, 3.5);
+
+        assert_eq!(layer.weights.borrow().gradient.clone().unwrap().data, vec![3.0, -3.5]);
+        assert_eq!(layer.biases.borrow().gradient.clone().unwrap().data, vec![0.0, 3.5]);
+    }
+}
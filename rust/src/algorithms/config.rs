@@ -0,0 +1,487 @@
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+
+use serde::{Deserialize, Serialize};
+
+use crate::algorithms::losses::Objective;
+use crate::algorithms::optimizers::{Adam, DEFAULT_BETA_1, DEFAULT_BETA_2, DEFAULT_EPSILON, DEFAULT_WEIGHT_DECAY, Optimizer, SGD};
+use crate::algorithms::schedules::{ConstantSchedule, CosineSchedule, DEFAULT_MINIMUM_LEARNING_RATE, DEFAULT_WARMUP_FRACTION, DEFAULT_WARMUP_LEARNING_RATE, InverseSqrtSchedule, LinearWarmupSchedule, LrSchedule, StepSchedule};
+use crate::one_bit_llm::parts::{Activation, FeedForward, LLMConfig, Normalization, PositionEncoding};
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OptimizerKind {
+    #[serde(rename = "sgd")]
+    SGD,
+    Adam,
+    #[serde(rename = "adamw")]
+    AdamW
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScheduleKind {
+    Constant,
+    LinearWarmup,
+    Cosine,
+    Step,
+    InverseSqrt
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ObjectiveKind {
+    CrossEntropy,
+    #[serde(rename = "mse")]
+    MSE
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PositionEncodingKind {
+    Learned,
+    Rotary,
+    #[serde(rename = "alibi")]
+    ALiBi
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NormalizationKind {
+    LayerNorm,
+    #[serde(rename = "rms_norm")]
+    RMSNorm
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FeedForwardKind {
+    Standard,
+    #[serde(rename = "swiglu")]
+    SwiGLU
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ActivationKind {
+    #[serde(rename = "gelu")]
+    GELU,
+    #[serde(rename = "exact_gelu")]
+    ExactGELU,
+    #[serde(rename = "relu")]
+    ReLU,
+    #[serde(rename = "squared_relu")]
+    SquaredReLU,
+    #[serde(rename = "silu")]
+    SiLU
+}
+
+
+// The settings a file leaves out to keep them unset, since TOML cannot write them.
+const OPTIONAL_SETTINGS: [&str; 1] = ["kv_head_count"];
+
+// Everything `train` needs, loaded from a TOML or JSON file and overridable from the command line. Keys missing
+// from the file keep their defaults. Limits and clipping thresholds use zero to mean "none" so that every
+// setting can be written in TOML, which has no null. The one exception is `kv_head_count`, which follows
+// `head_count` until it is set.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrainConfig {
+    // Data
+    pub data_path: String,
+    pub token_limit: usize,
+    pub checkpoint_path: String,
+
+    // Model dimensions. The vocabulary size defaults to the tokenizer's, and `kv_head_count` to `head_count`.
+    pub vocabulary_size: usize,
+    pub d_model: usize,
+    pub head_count: usize,
+    pub kv_head_count: Option<usize>,
+    pub ffn_inner_size: usize,
+    pub decoder_count: usize,
+    pub max_positions: usize,
+
+    // Model architecture. `rotary_base` is only used by rotary position encoding and `activation` only by the
+    // standard feed forward.
+    pub position_encoding: PositionEncodingKind,
+    pub rotary_base: f32,
+    pub normalization: NormalizationKind,
+    pub feed_forward: FeedForwardKind,
+    pub activation: ActivationKind,
+
+    // Regularization
+    pub attention_dropout: f32,
+    pub residual_dropout: f32,
+    pub dropout_seed: u64,
+
+    // Attention. Zero means no sliding window and untiled attention.
+    pub attention_window: usize,
+    pub attention_tile_size: usize,
+
+    // Batches of `batch_size` sequences, each `sequence_length` tokens long.
+    pub epoch_count: usize,
+    pub batches_per_epoch: usize,
+    pub batch_size: usize,
+    pub sequence_length: usize,
+
+    // Objective
+    pub objective: ObjectiveKind,
+    pub label_smoothing: f32,
+    pub z_loss_coefficient: f32,
+    // Cross-entropy ignores targets equal to this token, such as padding. Negative means no token is ignored.
+    pub ignore_index: i64,
+
+    // Optimizer
+    pub optimizer: OptimizerKind,
+    pub momentum: f32,
+    pub beta_1: f32,
+    pub beta_2: f32,
+    pub epsilon: f32,
+    pub weight_decay: f32,
+    pub gradient_clip_norm: f32,
+    pub gradient_clip_value: f32,
+
+    // Learning rate schedule. `learning_rate` is the peak rate, reached after warmup.
+    pub schedule: ScheduleKind,
+    pub learning_rate: f32,
+    pub minimum_learning_rate: f32,
+    pub warmup_learning_rate: f32,
+    pub warmup_fraction: f32,
+    pub step_size: usize,
+    pub gamma: f32,
+
+    // Logging
+    pub log_every: usize
+}
+
+impl Default for TrainConfig {
+    // The values `train` used before it was configurable, with the Python trainer's batch size.
+    fn default() -> TrainConfig {
+        TrainConfig {
+            data_path: "../../dataset/tokens.bin".to_string(),
+            token_limit: 4000,
+            checkpoint_path: String::new(),
+
+            vocabulary_size: 32000,
+            d_model: 512,
+            head_count: 8,
+            kv_head_count: None,
+            ffn_inner_size: 1024,
+            decoder_count: 6,
+            max_positions: 256,
+
+            position_encoding: PositionEncodingKind::Learned,
+            rotary_base: 10000.0,
+            normalization: NormalizationKind::LayerNorm,
+            feed_forward: FeedForwardKind::Standard,
+            activation: ActivationKind::GELU,
+
+            attention_dropout: 0.0,
+            residual_dropout: 0.0,
+            dropout_seed: 0,
+
+            attention_window: 0,
+            attention_tile_size: 0,
+
+            epoch_count: 100,
+            batches_per_epoch: 64,
+            batch_size: 8,
+            sequence_length: 256,
+
+            objective: ObjectiveKind::CrossEntropy,
+            label_smoothing: 0.0,
+            z_loss_coefficient: 0.0,
+            ignore_index: -1,
+
+            optimizer: OptimizerKind::AdamW,
+            momentum: 0.9,
+            beta_1: DEFAULT_BETA_1,
+            beta_2: DEFAULT_BETA_2,
+            epsilon: DEFAULT_EPSILON,
+            weight_decay: DEFAULT_WEIGHT_DECAY,
+            gradient_clip_norm: 1.0,
+            gradient_clip_value: 0.0,
+
+            schedule: ScheduleKind::Cosine,
+            learning_rate: 0.0002,
+            minimum_learning_rate: DEFAULT_MINIMUM_LEARNING_RATE,
+            warmup_learning_rate: DEFAULT_WARMUP_LEARNING_RATE,
+            warmup_fraction: DEFAULT_WARMUP_FRACTION,
+            step_size: 1000,
+            gamma: 0.5,
+
+            log_every: 1
+        }
+    }
+}
+
+fn invalid_input(message: String) -> Error {
+    Error::new(ErrorKind::InvalidInput, message)
+}
+
+impl TrainConfig {
+    // Reads a config file, as JSON when the path ends in ".json" and as TOML otherwise.
+    pub fn from_file(path: &str) -> Result<TrainConfig> {
+        let text: String = fs::read_to_string(path)?;
+
+        if path.ends_with(".json") {
+            serde_json::from_str(&text).map_err(|error| invalid_input(format!("{}: {}", path, error)))
+        } else {
+            toml::from_str(&text).map_err(|error| invalid_input(format!("{}: {}", path, error)))
+        }
+    }
+
+    // Builds the config from command line arguments: `--config <path>` loads a file first, and every other
+    // `--key value` or `--key=value` then overrides the setting of the same name.
+    pub fn from_args(args: &[String]) -> Result<TrainConfig> {
+        let mut config: TrainConfig = TrainConfig::default();
+        let mut overrides: Vec<(String, String)> = vec![];
+        let mut index: usize = 0;
+
+        while index < args.len() {
+            let argument: &str = match args[index].strip_prefix("--") {
+                Some(argument) => argument,
+                None => return Err(invalid_input(format!("Expected an option starting with --, found {}.", args[index])))
+            };
+
+            let (key, value) = match argument.split_once('=') {
+                Some((key, value)) => (key.to_string(), value.to_string()),
+                None => {
+                    index += 1;
+
+                    match args.get(index) {
+                        Some(value) => (argument.to_string(), value.clone()),
+                        None => return Err(invalid_input(format!("--{} is missing a value.", argument)))
+                    }
+                }
+            };
+
+            if key == "config" {
+                config = TrainConfig::from_file(&value)?;
+            } else {
+                overrides.push((key, value));
+            }
+
+            index += 1;
+        }
+
+        for (key, value) in overrides {
+            config.set(&key, &value)?;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    // Rejects settings the model constructors would panic on, so a bad config fails with a message before any
+    // data is loaded.
+    pub fn validate(&self) -> Result<()> {
+        if self.head_count == 0 || !self.d_model.is_multiple_of(self.head_count) {
+            return Err(invalid_input(format!("d_model ({}) must be divisible by head_count ({}).", self.d_model, self.head_count)));
+        }
+
+        let kv_head_count: usize = self.kv_head_count.unwrap_or(self.head_count);
+
+        if kv_head_count == 0 || !self.head_count.is_multiple_of(kv_head_count) {
+            return Err(invalid_input(format!("head_count ({}) must be divisible by kv_head_count ({}).", self.head_count, kv_head_count)));
+        }
+
+        // Rotary and ALiBi encode positions without a table, so only learned positions cap the sequence length.
+        if self.position_encoding == PositionEncodingKind::Learned && self.sequence_length > self.max_positions {
+            return Err(invalid_input(format!("sequence_length ({}) must not exceed max_positions ({}) with learned positions.", self.sequence_length, self.max_positions)));
+        }
+
+        // A zero would leave the model without tokens, `train` with nothing to batch, or `log_every` silently switching step logging off.
+        for (key, value) in [("vocabulary_size", self.vocabulary_size), ("batch_size", self.batch_size), ("sequence_length", self.sequence_length), ("log_every", self.log_every)] {
+            if value == 0 {
+                return Err(invalid_input(format!("{} must be at least 1.", key)));
+            }
+        }
+
+        for (key, rate) in [("attention_dropout", self.attention_dropout), ("residual_dropout", self.residual_dropout)] {
+            if !(0.0..1.0).contains(&rate) {
+                return Err(invalid_input(format!("{} ({}) must be in [0, 1).", key, rate)));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Sets one setting from its textual value, which is parsed as a TOML value of the setting's type
+    // (so strings need no quotes).
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        let mut table: toml::Table = match toml::Value::try_from(&*self) {
+            Ok(toml::Value::Table(table)) => table,
+            _ => return Err(invalid_input("The config could not be serialized.".to_string()))
+        };
+
+        // Optional settings that are unset are left out of the table, so they are looked up by name.
+        let known: bool = table.contains_key(key) || OPTIONAL_SETTINGS.contains(&key);
+
+        let parsed: toml::Value = match table.get(key) {
+            Some(toml::Value::String(_)) => toml::Value::String(value.to_string()),
+            _ if known => match format!("value = {}", value).parse::<toml::Table>() {
+                Ok(mut parsed) => parsed.remove("value").unwrap(),
+                Err(_) => toml::Value::String(value.to_string())
+            },
+            _ => return Err(invalid_input(format!("Unknown setting {}.", key)))
+        };
+
+        table.insert(key.to_string(), parsed);
+
+        *self = toml::Value::Table(table).try_into().map_err(|error| invalid_input(format!("--{} {}: {}", key, value, error)))?;
+        Ok(())
+    }
+
+    pub fn model_config(&self) -> LLMConfig {
+        let mut config: LLMConfig = LLMConfig::new(self.vocabulary_size, self.d_model, self.head_count, self.ffn_inner_size, self.decoder_count, self.max_positions);
+
+        config.kv_head_count = self.kv_head_count.unwrap_or(self.head_count);
+
+        config.position_encoding = match self.position_encoding {
+            PositionEncodingKind::Learned => PositionEncoding::Learned,
+            PositionEncodingKind::Rotary => PositionEncoding::Rotary { base: self.rotary_base },
+            PositionEncodingKind::ALiBi => PositionEncoding::ALiBi
+        };
+
+        config.normalization = match self.normalization {
+            NormalizationKind::LayerNorm => Normalization::LayerNorm,
+            NormalizationKind::RMSNorm => Normalization::RMSNorm
+        };
+
+        config.feed_forward = match self.feed_forward {
+            FeedForwardKind::Standard => FeedForward::Standard(match self.activation {
+                ActivationKind::GELU => Activation::GELU,
+                ActivationKind::ExactGELU => Activation::ExactGELU,
+                ActivationKind::ReLU => Activation::ReLU,
+                ActivationKind::SquaredReLU => Activation::SquaredReLU,
+                ActivationKind::SiLU => Activation::SiLU
+            }),
+            FeedForwardKind::SwiGLU => FeedForward::SwiGLU
+        };
+
+        config.attention_dropout = self.attention_dropout;
+        config.residual_dropout = self.residual_dropout;
+        config.dropout_seed = self.dropout_seed;
+
+        config.attention_window = if self.attention_window > 0 { Some(self.attention_window) } else { None };
+        config.attention_tile_size = if self.attention_tile_size > 0 { Some(self.attention_tile_size) } else { None };
+        config
+    }
+
+    pub fn objective(&self) -> Objective {
+        match self.objective {
+            ObjectiveKind::CrossEntropy => Objective::CrossEntropy {
+                label_smoothing: self.label_smoothing,
+                ignore_index: if self.ignore_index >= 0 { Some(self.ignore_index as usize) } else { None }
+            },
+            ObjectiveKind::MSE => Objective::MSE
+        }
+    }
+
+    pub fn optimizer(&self) -> Box<dyn Optimizer> {
+        match self.optimizer {
+            OptimizerKind::SGD => Box::new(SGD::new(self.momentum)),
+            OptimizerKind::Adam => {
+                let mut optimizer: Adam = Adam::new(self.beta_1, self.beta_2, self.epsilon);
+
+                optimizer.weight_decay = self.weight_decay;
+                Box::new(optimizer)
+            },
+            OptimizerKind::AdamW => Box::new(Adam::adamw(self.beta_1, self.beta_2, self.epsilon, self.weight_decay))
+        }
+    }
+
+    pub fn total_steps(&self) -> usize {
+        self.epoch_count * self.batches_per_epoch
+    }
+
+    pub fn schedule(&self) -> Box<dyn LrSchedule> {
+        let total_steps: usize = self.total_steps();
+        let warmup_steps: usize = (self.warmup_fraction * total_steps as f32).ceil() as usize;
+
+        match self.schedule {
+            ScheduleKind::Constant => Box::new(ConstantSchedule::new(self.learning_rate)),
+            ScheduleKind::LinearWarmup => Box::new(LinearWarmupSchedule::new(self.learning_rate, self.warmup_learning_rate, warmup_steps)),
+            ScheduleKind::Cosine => Box::new(CosineSchedule::new(self.learning_rate, self.minimum_learning_rate, self.warmup_learning_rate, warmup_steps, total_steps)),
+            ScheduleKind::Step => Box::new(StepSchedule::new(self.learning_rate, self.step_size, self.gamma)),
+            ScheduleKind::InverseSqrt => Box::new(InverseSqrtSchedule::new(self.learning_rate, warmup_steps))
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(text: &str) -> Vec<String> {
+        text.split_whitespace().map(|argument| argument.to_string()).collect()
+    }
+
+    #[test]
+    fn overrides_replace_defaults() {
+        let config: TrainConfig = TrainConfig::from_args(&args("--head_count 4 --kv_head_count=2 --optimizer sgd --learning_rate 1e-3")).unwrap();
+
+        assert_eq!((config.head_count, config.kv_head_count), (4, Some(2)));
+        assert_eq!(config.optimizer, OptimizerKind::SGD);
+        assert_eq!(config.learning_rate, 1e-3);
+        assert_eq!(config.d_model, TrainConfig::default().d_model);
+    }
+
+    #[test]
+    fn model_settings_reach_the_model_config() {
+        let config: TrainConfig = TrainConfig::from_args(&args("--position_encoding rotary --rotary_base 500 --normalization rms_norm --feed_forward standard --activation squared_relu --attention_dropout 0.1 --dropout_seed 7 --attention_window 64 --attention_tile_size 32 --ignore_index 0")).unwrap();
+        let model_config: LLMConfig = config.model_config();
+
+        assert_eq!(model_config.position_encoding, PositionEncoding::Rotary { base: 500.0 });
+        assert_eq!(model_config.normalization, Normalization::RMSNorm);
+        assert_eq!(model_config.feed_forward, FeedForward::Standard(Activation::SquaredReLU));
+        assert_eq!((model_config.attention_dropout, model_config.residual_dropout, model_config.dropout_seed), (0.1, 0.0, 7));
+        assert_eq!((model_config.attention_window, model_config.attention_tile_size), (Some(64), Some(32)));
+        assert_eq!(config.objective(), Objective::CrossEntropy { label_smoothing: 0.0, ignore_index: Some(0) });
+
+        let defaults: LLMConfig = TrainConfig::default().model_config();
+        assert_eq!((defaults.attention_window, defaults.attention_tile_size), (None, None));
+        assert_eq!(TrainConfig::default().objective(), Objective::CrossEntropy { label_smoothing: 0.0, ignore_index: None });
+    }
+
+    #[test]
+    fn invalid_settings_are_rejected() {
+        assert!(TrainConfig::from_args(&args("--kv_head_count 0")).is_err());
+        assert!(TrainConfig::from_args(&args("--head_count 8 --kv_head_count 3")).is_err());
+        assert!(TrainConfig::from_args(&args("--d_model 100 --head_count 8")).is_err());
+        assert!(TrainConfig::from_args(&args("--head_count 0")).is_err());
+        assert!(TrainConfig::from_args(&args("--residual_dropout 1.0")).is_err());
+        assert!(TrainConfig::from_args(&args("--log_every 0")).is_err());
+        assert!(TrainConfig::from_args(&args("--batch_size 0")).is_err());
+        assert!(TrainConfig::from_args(&args("--sequence_length 0")).is_err());
+        assert!(TrainConfig::from_args(&args("--vocabulary_size 0")).is_err());
+        assert!(TrainConfig::from_args(&args("--max_positions 128 --sequence_length 256")).is_err());
+    }
+
+    #[test]
+    fn kv_head_count_follows_head_count_until_set() {
+        let config: TrainConfig = TrainConfig::from_args(&args("--head_count 4")).unwrap();
+        assert_eq!(config.kv_head_count, None);
+        assert_eq!(config.model_config().kv_head_count, 4);
+
+        let file_config: TrainConfig = toml::from_str("head_count = 4\nkv_head_count = 2").unwrap();
+        assert_eq!(file_config.model_config().kv_head_count, 2);
+    }
+
+    #[test]
+    fn sequence_length_is_capped_by_learned_positions_only() {
+        assert!(TrainConfig::from_args(&args("--max_positions 128 --sequence_length 128")).is_ok());
+        assert!(TrainConfig::from_args(&args("--position_encoding rotary --max_positions 128 --sequence_length 256")).is_ok());
+        assert!(TrainConfig::from_args(&args("--position_encoding alibi --max_positions 128 --sequence_length 256")).is_ok());
+    }
+
+    #[test]
+    fn vocabulary_size_defaults_to_the_tokenizer_and_reaches_the_model_config() {
+        assert_eq!(TrainConfig::default().model_config().vocabulary_size, 32000);
+
+        let config: TrainConfig = TrainConfig::from_args(&args("--vocabulary_size 256")).unwrap();
+        assert_eq!(config.model_config().vocabulary_size, 256);
+    }
+}
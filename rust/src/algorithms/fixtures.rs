@@ -0,0 +1,12 @@
+use crate::matrix::matrix::Matrix;
+use crate::one_bit_llm::parts::Dense;
+
+// A Dense layer with the given gradients already accumulated: `weight_gradient` fills the (input_size x nodes)
+// weights row by row and `bias_gradient` the (1 x nodes) biases.
+pub fn dense_with_gradients(input_size: usize, nodes: usize, weight_gradient: &[f32], bias_gradient: &[f32]) -> Dense {
+    let layer: Dense = Dense::new(nodes, input_size, -1.0, 1.0);
+
+    layer.weights.accumulate_gradient(Matrix { rows: input_size, cols: nodes, data: weight_gradient.to_vec() });
+    layer.biases.accumulate_gradient(Matrix { rows: 1, cols: nodes, data: bias_gradient.to_vec() });
+    layer
+}
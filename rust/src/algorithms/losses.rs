@@ -0,0 +1,478 @@
+use crate::matrix::matrix::Matrix;
+use crate::one_bit_llm::parts::Embedding;
+
+// A loss over a (rows x vocabulary_size) matrix of logits. The meaning of `targets` depends on the loss.
+pub trait Loss {
+    // Returns the loss and its gradient with respect to the logits.
+    fn compute(&self, logits: &Matrix<f32>, targets: &Matrix<f32>) -> (f32, Matrix<f32>);
+}
+
+
+// log(softmax(row)) for one row, computed as x - max - log(sum(exp(x - max))) for numerical stability.
+fn row_log_softmax(logits: &Matrix<f32>, row: usize, temperature: f32) -> Vec<f32> {
+    let mut maximum: f32 = f32::NEG_INFINITY;
+
+    for col in 0..logits.cols {
+        maximum = maximum.max(logits.get(row, col) / temperature);
+    }
+
+    let mut exponential_total: f32 = 0.0;
+
+    for col in 0..logits.cols {
+        exponential_total += (logits.get(row, col) / temperature - maximum).exp();
+    }
+
+    let log_total: f32 = exponential_total.ln() + maximum;
+
+    (0..logits.cols).map(|col| logits.get(row, col) / temperature - log_total).collect()
+}
+
+
+// Softmax cross-entropy over vocabulary logits with sparse integer targets, matching
+// `tf.keras.losses.SparseCategoricalCrossentropy(from_logits = True)` in the Python trainer.
+// The targets are a (rows x 1) column of token indices, as built by `Embedding::indices_to_matrix`.
+// With label smoothing e, the target distribution is (1 - e) * one_hot + e / vocabulary_size.
+// Rows whose target equals `ignore_index` (e.g. padding) contribute neither loss nor gradient.
+pub struct CrossEntropyLoss {
+    pub label_smoothing: f32,
+    pub ignore_index: Option<usize>
+}
+
+impl CrossEntropyLoss {
+    pub fn new() -> CrossEntropyLoss {
+        CrossEntropyLoss {
+            label_smoothing: 0.0,
+            ignore_index: None
+        }
+    }
+}
+
+impl Loss for CrossEntropyLoss {
+    // The loss is averaged over the scored rows. The softmax and the loss are differentiated together,
+    // giving (softmax - target_distribution) / row_count.
+    fn compute(&self, logits: &Matrix<f32>, targets: &Matrix<f32>) -> (f32, Matrix<f32>) {
+        let mut gradients: Matrix<f32> = Matrix::new(logits.rows, logits.cols, 0.0);
+
+        if targets.rows != logits.rows {
+            println!("Cross Entropy Error - {} targets given for {} rows of logits.", targets.rows, logits.rows);
+            return (0.0, gradients);
+        }
+
+        let targets: Vec<usize> = (0..targets.rows).map(|row| targets.get(row, 0) as usize).collect();
+        let scored_rows: Vec<usize> = (0..logits.rows).filter(|&row| self.ignore_index != Some(targets[row])).collect();
+
+        if scored_rows.is_empty() {
+            return (0.0, gradients);
+        }
+
+        let vocabulary_size: usize = logits.cols;
+        let smoothing_weight: f32 = self.label_smoothing / vocabulary_size as f32;
+        let mut total_loss: f32 = 0.0;
+
+        for &row in scored_rows.iter() {
+            if targets[row] >= vocabulary_size {
+                println!("Cross Entropy Error - Target {} is outside the vocabulary of {}.", targets[row], vocabulary_size);
+                continue;
+            }
+
+            let log_probabilities: Vec<f32> = row_log_softmax(logits, row, 1.0);
+
+            for (col, log_probability) in log_probabilities.into_iter().enumerate() {
+                let target_probability: f32 = if col == targets[row] { 1.0 - self.label_smoothing + smoothing_weight } else { smoothing_weight };
+
+                total_loss -= target_probability * log_probability;
+                gradients.set(row, col, (log_probability.exp() - target_probability) / scored_rows.len() as f32);
+            }
+        }
+
+        (total_loss / scored_rows.len() as f32, gradients)
+    }
+}
+
+
+// Mean squared error between the logits and a target matrix of the same shape, averaged over every element.
+pub struct MSELoss {}
+
+impl MSELoss {
+    pub fn new() -> MSELoss {
+        MSELoss {}
+    }
+}
+
+impl Loss for MSELoss {
+    fn compute(&self, logits: &Matrix<f32>, targets: &Matrix<f32>) -> (f32, Matrix<f32>) {
+        if logits.rows != targets.rows || logits.cols != targets.cols {
+            println!("MSE Error - Targets are ({}, {}) but logits are ({}, {}).", targets.rows, targets.cols, logits.rows, logits.cols);
+            return (0.0, Matrix::new(logits.rows, logits.cols, 0.0));
+        }
+
+        let element_count: f32 = logits.data.len() as f32;
+        let differences: Matrix<f32> = logits.clone() - targets.clone();
+        let total_loss: f32 = differences.data.iter().map(|difference| difference * difference).sum();
+
+        (total_loss / element_count, differences * (2.0 / element_count))
+    }
+}
+
+
+// KL(teacher || student) for distillation, where the targets are the teacher's logits for the same rows.
+// Both distributions are softened by `temperature`, and the loss is scaled by temperature^2 so that gradient
+// magnitudes stay comparable across temperatures (Hinton et al.). The loss is averaged over rows.
+pub struct KLDivergenceLoss {
+    pub temperature: f32
+}
+
+impl KLDivergenceLoss {
+    pub fn new(temperature: f32) -> KLDivergenceLoss {
+        KLDivergenceLoss {
+            temperature: temperature
+        }
+    }
+}
+
+impl Loss for KLDivergenceLoss {
+    fn compute(&self, logits: &Matrix<f32>, targets: &Matrix<f32>) -> (f32, Matrix<f32>) {
+        let mut gradients: Matrix<f32> = Matrix::new(logits.rows, logits.cols, 0.0);
+
+        if logits.rows != targets.rows || logits.cols != targets.cols {
+            println!("KL Divergence Error - Teacher logits are ({}, {}) but student logits are ({}, {}).", targets.rows, targets.cols, logits.rows, logits.cols);
+            return (0.0, gradients);
+        }
+
+        let mut total_loss: f32 = 0.0;
+
+        for row in 0..logits.rows {
+            let student: Vec<f32> = row_log_softmax(logits, row, self.temperature);
+            let teacher: Vec<f32> = row_log_softmax(targets, row, self.temperature);
+
+            for col in 0..logits.cols {
+                let teacher_probability: f32 = teacher[col].exp();
+
+                total_loss += teacher_probability * (teacher[col] - student[col]);
+
+                // d/dx of T^2 * KL through softmax(x / T) is T * (student - teacher).
+                gradients.set(row, col, self.temperature * (student[col].exp() - teacher_probability) / logits.rows as f32);
+            }
+        }
+
+        (self.temperature * self.temperature * total_loss / logits.rows as f32, gradients)
+    }
+}
+
+
+// The z-loss regularizer from PaLM, coefficient * mean(log(sum(exp(logits)))^2), which keeps the softmax
+// normalizer close to one and the logits from drifting. It has no targets, so `targets` is ignored.
+pub struct ZLoss {
+    pub coefficient: f32
+}
+
+impl ZLoss {
+    pub fn new(coefficient: f32) -> ZLoss {
+        ZLoss {
+            coefficient: coefficient
+        }
+    }
+}
+
+impl Loss for ZLoss {
+    fn compute(&self, logits: &Matrix<f32>, _targets: &Matrix<f32>) -> (f32, Matrix<f32>) {
+        let mut gradients: Matrix<f32> = Matrix::new(logits.rows, logits.cols, 0.0);
+        let mut total_loss: f32 = 0.0;
+
+        for row in 0..logits.rows {
+            let log_probabilities: Vec<f32> = row_log_softmax(logits, row, 1.0);
+
+            // log_softmax(x)_j = x_j - logsumexp(x), so any column recovers the normalizer.
+            let logsumexp: f32 = logits.get(row, 0) - log_probabilities[0];
+            total_loss += logsumexp * logsumexp;
+
+            for (col, log_probability) in log_probabilities.into_iter().enumerate() {
+                gradients.set(row, col, 2.0 * self.coefficient * logsumexp * log_probability.exp() / logits.rows as f32);
+            }
+        }
+
+        (self.coefficient * total_loss / logits.rows as f32, gradients)
+    }
+}
+
+
+// The sum of several losses evaluated on the same logits and targets, e.g. cross-entropy plus z-loss.
+pub struct CombinedLoss {
+    pub losses: Vec<Box<dyn Loss>>
+}
+
+impl CombinedLoss {
+    pub fn new(losses: Vec<Box<dyn Loss>>) -> CombinedLoss {
+        CombinedLoss {
+            losses: losses
+        }
+    }
+}
+
+impl Loss for CombinedLoss {
+    fn compute(&self, logits: &Matrix<f32>, targets: &Matrix<f32>) -> (f32, Matrix<f32>) {
+        let mut total_loss: f32 = 0.0;
+        let mut gradients: Matrix<f32> = Matrix::new(logits.rows, logits.cols, 0.0);
+
+        for loss in self.losses.iter() {
+            let (value, loss_gradients) = loss.compute(logits, targets);
+
+            total_loss += value;
+            gradients = gradients + loss_gradients;
+        }
+
+        (total_loss, gradients)
+    }
+}
+
+
+// The next-token objectives `train` can optimize. Distillation needs a teacher, so KL divergence is not listed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Objective {
+    CrossEntropy { label_smoothing: f32, ignore_index: Option<usize> },
+    // Regresses the logits onto the one-hot encoding of the next token.
+    MSE
+}
+
+impl Objective {
+    // The objective's loss, with z-loss added when `z_loss_coefficient` is above zero.
+    pub fn loss_function(&self, z_loss_coefficient: f32) -> Box<dyn Loss> {
+        let objective_loss: Box<dyn Loss> = match *self {
+            Objective::CrossEntropy { label_smoothing, ignore_index } => Box::new(CrossEntropyLoss {
+                label_smoothing: label_smoothing,
+                ignore_index: ignore_index
+            }),
+            Objective::MSE => Box::new(MSELoss::new())
+        };
+
+        if z_loss_coefficient > 0.0 {
+            return Box::new(CombinedLoss::new(vec![objective_loss, Box::new(ZLoss::new(z_loss_coefficient))]));
+        }
+
+        objective_loss
+    }
+
+    // The target matrix the objective's loss expects for the next tokens `tokens`.
+    pub fn targets(&self, tokens: &[usize], vocabulary_size: usize) -> Matrix<f32> {
+        match *self {
+            Objective::CrossEntropy { .. } => Embedding::indices_to_matrix(tokens),
+            Objective::MSE => {
+                let mut one_hot: Matrix<f32> = Matrix::new(tokens.len(), vocabulary_size, 0.0);
+
+                for (row, &token) in tokens.iter().enumerate() {
+                    if token >= vocabulary_size {
+                        println!("MSE Error - Target {} is outside the vocabulary of {}.", token, vocabulary_size);
+                        return Matrix::new(0, 0, 0.0);
+                    }
+
+                    one_hot.set(row, token, 1.0);
+                }
+
+                one_hot
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matrix(rows: usize, cols: usize, data: Vec<f32>) -> Matrix<f32> {
+        Matrix { rows: rows, cols: cols, data: data }
+    }
+
+    fn logits() -> Matrix<f32> {
+        matrix(3, 4, vec![1.0, 2.0, 3.0, 0.5, -1.0, 0.25, 2.5, -0.75, 0.0, 1.5, -2.0, 1.0])
+    }
+
+    // Compares every element of the analytic gradient with a central difference of the loss.
+    fn assert_gradient_matches_finite_differences(loss: &dyn Loss, logits: &Matrix<f32>, targets: &Matrix<f32>) {
+        let step: f32 = 1e-2;
+        let (_, gradients) = loss.compute(logits, targets);
+
+        for i in 0..logits.data.len() {
+            let (mut plus, mut minus) = (logits.clone(), logits.clone());
+            plus.data[i] += step;
+            minus.data[i] -= step;
+
+            let expected: f32 = (loss.compute(&plus, targets).0 - loss.compute(&minus, targets).0) / (2.0 * step);
+            assert!((gradients.data[i] - expected).abs() < 1e-3, "logit {}: expected {}, got {}", i, expected, gradients.data[i]);
+        }
+    }
+
+    // -sum_j(q_j * log_softmax(row)_j) for a target distribution q, in f64.
+    fn row_cross_entropy(row: &[f32], target_distribution: &[f64]) -> f64 {
+        let log_total: f64 = row.iter().map(|&value| (value as f64).exp()).sum::<f64>().ln();
+        row.iter().zip(target_distribution.iter()).map(|(&value, &weight)| -weight * (value as f64 - log_total)).sum()
+    }
+
+    #[test]
+    fn cross_entropy_matches_known_values() {
+        let targets: Matrix<f32> = Embedding::indices_to_matrix(&[2, 0, 1]);
+        let (loss, _) = CrossEntropyLoss::new().compute(&logits(), &targets);
+
+        let one_hots: [[f64; 4]; 3] = [[0.0, 0.0, 1.0, 0.0], [1.0, 0.0, 0.0, 0.0], [0.0, 1.0, 0.0, 0.0]];
+        let expected: f64 = (0..3).map(|row| row_cross_entropy(&logits().data[4 * row..4 * row + 4], &one_hots[row])).sum::<f64>() / 3.0;
+        assert!((loss as f64 - expected).abs() < 1e-5, "expected {}, got {}", expected, loss);
+
+        // Equal logits give a uniform prediction, whose loss is ln(vocabulary_size) for any target.
+        let (uniform_loss, _) = CrossEntropyLoss::new().compute(&Matrix::new(3, 4, 0.7), &targets);
+        assert!((uniform_loss - 4.0_f32.ln()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn cross_entropy_gradient_matches_finite_differences() {
+        let targets: Matrix<f32> = Embedding::indices_to_matrix(&[2, 0, 1]);
+        let (_, gradients) = CrossEntropyLoss::new().compute(&logits(), &targets);
+
+        // The fused gradient is (softmax - one_hot) / rows, so each row sums to zero.
+        for row in 0..3 {
+            let total: f32 = (0..4).map(|col| gradients.get(row, col)).sum();
+            assert!(total.abs() < 1e-6);
+        }
+
+        assert_gradient_matches_finite_differences(&CrossEntropyLoss::new(), &logits(), &targets);
+    }
+
+    #[test]
+    fn label_smoothing_spreads_the_target_over_the_vocabulary() {
+        let targets: Matrix<f32> = Embedding::indices_to_matrix(&[2, 0, 1]);
+        let mut smoothed: CrossEntropyLoss = CrossEntropyLoss::new();
+        smoothed.label_smoothing = 0.2;
+
+        let (loss, _) = smoothed.compute(&logits(), &targets);
+
+        // (1 - 0.2) * one_hot + 0.2 / 4 puts 0.85 on the target and 0.05 on every other token.
+        let expected: f64 = [2, 0, 1].iter().enumerate().map(|(row, &target)| {
+            let distribution: Vec<f64> = (0..4).map(|col| if col == target { 0.85 } else { 0.05 }).collect();
+            row_cross_entropy(&logits().data[4 * row..4 * row + 4], &distribution)
+        }).sum::<f64>() / 3.0;
+
+        assert!((loss as f64 - expected).abs() < 1e-5, "expected {}, got {}", expected, loss);
+        assert_gradient_matches_finite_differences(&smoothed, &logits(), &targets);
+    }
+
+    #[test]
+    fn ignored_rows_get_no_loss_or_gradient() {
+        let mut loss_function: CrossEntropyLoss = CrossEntropyLoss::new();
+        loss_function.ignore_index = Some(3);
+
+        let (loss, gradients) = loss_function.compute(&logits(), &Embedding::indices_to_matrix(&[2, 3, 1]));
+
+        // The mean is over the two scored rows only, so it matches a loss over just those rows.
+        let scored_logits: Matrix<f32> = Matrix::concat_rows(vec![logits().slice_rows(0, 1), logits().slice_rows(2, 3)]);
+        let (expected_loss, expected_gradients) = CrossEntropyLoss::new().compute(&scored_logits, &Embedding::indices_to_matrix(&[2, 1]));
+
+        assert!((loss - expected_loss).abs() < 1e-6);
+        assert!((0..4).all(|col| gradients.get(1, col) == 0.0));
+        assert_eq!(gradients.slice_rows(0, 1).data, expected_gradients.slice_rows(0, 1).data);
+        assert_eq!(gradients.slice_rows(2, 3).data, expected_gradients.slice_rows(1, 2).data);
+    }
+
+    fn teacher_logits() -> Matrix<f32> {
+        matrix(3, 4, vec![0.5, 1.0, -0.5, 2.0, 1.5, -1.0, 0.0, 0.25, -0.5, 2.0, 1.0, -1.5])
+    }
+
+    // log(sum(exp(row / temperature))) and the softmax of row / temperature, in f64.
+    fn row_softmax(row: &[f32], temperature: f64) -> (f64, Vec<f64>) {
+        let logsumexp: f64 = row.iter().map(|&value| (value as f64 / temperature).exp()).sum::<f64>().ln();
+        (logsumexp, row.iter().map(|&value| (value as f64 / temperature - logsumexp).exp()).collect())
+    }
+
+    #[test]
+    fn mse_matches_known_values_and_finite_differences() {
+        let targets: Matrix<f32> = matrix(3, 4, vec![1.0, 0.0, 3.0, 0.0, 0.0, 0.0, 2.5, -1.75, 1.0, 1.5, 0.0, 1.0]);
+        let (loss, gradients) = MSELoss::new().compute(&logits(), &targets);
+
+        // The differences are 0, 2, 0, 0.5, -1, 0.25, 0, 1, -1, 0, -2 and 0.
+        assert!((loss - 11.3125 / 12.0).abs() < 1e-6);
+        assert!((gradients.get(0, 1) - 2.0 * 2.0 / 12.0).abs() < 1e-6);
+
+        assert_gradient_matches_finite_differences(&MSELoss::new(), &logits(), &targets);
+    }
+
+    #[test]
+    fn mse_targets_reject_a_token_outside_the_vocabulary() {
+        let targets: Matrix<f32> = Objective::MSE.targets(&[1, 0, 3], 4);
+        assert_eq!(targets.data, vec![0.0, 1.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0]);
+
+        let rejected: Matrix<f32> = Objective::MSE.targets(&[1, 4, 3], 4);
+        assert_eq!((rejected.rows, rejected.cols), (0, 0));
+    }
+
+    #[test]
+    fn kl_divergence_is_scaled_by_the_squared_temperature() {
+        for temperature in [1.0, 2.0] {
+            let (loss, _) = KLDivergenceLoss::new(temperature).compute(&logits(), &teacher_logits());
+
+            let expected: f64 = (0..3).map(|row| {
+                let (_, student) = row_softmax(&logits().data[4 * row..4 * row + 4], temperature as f64);
+                let (_, teacher) = row_softmax(&teacher_logits().data[4 * row..4 * row + 4], temperature as f64);
+
+                teacher.iter().zip(student.iter()).map(|(t, s)| t * (t.ln() - s.ln())).sum::<f64>()
+            }).sum::<f64>() * (temperature * temperature) as f64 / 3.0;
+
+            assert!((loss as f64 - expected).abs() < 1e-5, "temperature {}: expected {}, got {}", temperature, expected, loss);
+            assert_gradient_matches_finite_differences(&KLDivergenceLoss::new(temperature), &logits(), &teacher_logits());
+        }
+
+        // A student that matches its teacher has nothing left to learn.
+        let (loss, gradients) = KLDivergenceLoss::new(2.0).compute(&logits(), &logits());
+        assert!(loss.abs() < 1e-6 && gradients.data.iter().all(|gradient| gradient.abs() < 1e-6));
+    }
+
+    #[test]
+    fn z_loss_matches_known_values_and_finite_differences() {
+        let z_loss: ZLoss = ZLoss::new(0.1);
+        let (loss, gradients) = z_loss.compute(&logits(), &Matrix::new(0, 0, 0.0));
+
+        let mut expected_loss: f64 = 0.0;
+
+        for row in 0..3 {
+            let (logsumexp, softmax) = row_softmax(&logits().data[4 * row..4 * row + 4], 1.0);
+            expected_loss += 0.1 * logsumexp * logsumexp / 3.0;
+
+            // d/dx of c * lse^2 / rows is 2 * c * lse * softmax(x) / rows.
+            for (col, probability) in softmax.into_iter().enumerate() {
+                let expected_gradient: f64 = 2.0 * 0.1 * logsumexp * probability / 3.0;
+                assert!((gradients.get(row, col) as f64 - expected_gradient).abs() < 1e-6);
+            }
+        }
+
+        assert!((loss as f64 - expected_loss).abs() < 1e-5, "expected {}, got {}", expected_loss, loss);
+        assert_gradient_matches_finite_differences(&z_loss, &logits(), &Matrix::new(0, 0, 0.0));
+    }
+
+    #[test]
+    fn combined_loss_adds_its_losses() {
+        let targets: Matrix<f32> = Embedding::indices_to_matrix(&[2, 0, 1]);
+        let combined: CombinedLoss = CombinedLoss::new(vec![Box::new(CrossEntropyLoss::new()), Box::new(ZLoss::new(0.1))]);
+
+        let (loss, gradients) = combined.compute(&logits(), &targets);
+        let (cross_entropy, cross_entropy_gradients) = CrossEntropyLoss::new().compute(&logits(), &targets);
+        let (z_loss, z_loss_gradients) = ZLoss::new(0.1).compute(&logits(), &targets);
+
+        assert!((loss - (cross_entropy + z_loss)).abs() < 1e-6);
+        assert_eq!(gradients.data, (cross_entropy_gradients + z_loss_gradients).data);
+        assert_gradient_matches_finite_differences(&combined, &logits(), &targets);
+    }
+
+    #[test]
+    fn objectives_build_the_targets_their_loss_expects() {
+        let cross_entropy: Objective = Objective::CrossEntropy { label_smoothing: 0.0, ignore_index: None };
+        let column: Matrix<f32> = cross_entropy.targets(&[2, 0, 1], 4);
+        assert_eq!((column.rows, column.cols, column.data.clone()), (3, 1, vec![2.0, 0.0, 1.0]));
+
+        let one_hot: Matrix<f32> = Objective::MSE.targets(&[2, 0, 1], 4);
+        assert_eq!((one_hot.rows, one_hot.cols), (3, 4));
+        assert_eq!(one_hot.data, vec![0.0, 0.0, 1.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0]);
+
+        // A z-loss coefficient above zero adds z-loss onto the objective's own loss.
+        let (loss, _) = cross_entropy.loss_function(0.1).compute(&logits(), &column);
+        let (cross_entropy_loss, _) = CrossEntropyLoss::new().compute(&logits(), &column);
+        let (z_loss, _) = ZLoss::new(0.1).compute(&logits(), &column);
+        assert!((loss - (cross_entropy_loss + z_loss)).abs() < 1e-6);
+    }
+}
@@ -1 +1,8 @@
-pub mod train;
\ No newline at end of file
+pub mod train;
+pub mod losses;
+pub mod optimizers;
+pub mod schedules;
+pub mod clipping;
+pub mod config;
+#[cfg(test)]
+pub mod fixtures;
\ No newline at end of file
@@ -0,0 +1,205 @@
+use std::collections::HashMap;
+
+use crate::matrix::matrix::Matrix;
+use crate::one_bit_llm::parts::Layer;
+
+// Defaults of the Python trainer's `tf.keras.optimizers.AdamW`.
+pub const DEFAULT_BETA_1: f32 = 0.9;
+pub const DEFAULT_BETA_2: f32 = 0.95;
+pub const DEFAULT_EPSILON: f32 = 1e-8;
+pub const DEFAULT_WEIGHT_DECAY: f32 = 0.1;
+
+// Updates parameters from the gradients accumulated by `Layer::calculate_gradients`. Optimizer state is kept
+// per parameter, keyed by the names from `Layer::parameters`, so one optimizer must only be used with one model.
+pub trait Optimizer {
+    fn step(&mut self, layer: &mut dyn Layer, learning_rate: f32);
+}
+
+
+// Gradient descent with (heavy ball) momentum: v = momentum * v + g, w = w - learning_rate * v.
+// A momentum of zero is plain gradient descent.
+pub struct SGD {
+    pub momentum: f32,
+    velocities: HashMap<String, Matrix<f32>>
+}
+
+impl SGD {
+    pub fn new(momentum: f32) -> SGD {
+        SGD {
+            momentum: momentum,
+            velocities: HashMap::new()
+        }
+    }
+}
+
+impl Optimizer for SGD {
+    fn step(&mut self, layer: &mut dyn Layer, learning_rate: f32) {
+        for (name, parameter) in layer.parameters_mut() {
+            let mut data = parameter.borrow_mut();
+
+            let gradient: Matrix<f32> = match data.gradient.clone() {
+                Some(gradient) => gradient,
+                None => continue
+            };
+
+            let velocity: Matrix<f32> = match self.velocities.get(&name) {
+                Some(velocity) => velocity.clone() * self.momentum + gradient,
+                None => gradient
+            };
+
+            data.value = data.value.clone() - velocity.clone() * learning_rate;
+            self.velocities.insert(name, velocity);
+        }
+    }
+}
+
+
+// Adam with bias-corrected moment estimates. Weight decay is either added to the gradient as L2 regularization
+// (Adam) or, when `decoupled_weight_decay` is set, applied directly to the weights as in AdamW:
+// w = w - learning_rate * (m_hat / (sqrt(v_hat) + epsilon) + weight_decay * w).
+pub struct Adam {
+    pub beta_1: f32,
+    pub beta_2: f32,
+    pub epsilon: f32,
+    pub weight_decay: f32,
+    pub decoupled_weight_decay: bool,
+
+    step_count: i32,
+    first_moments: HashMap<String, Matrix<f32>>,
+    second_moments: HashMap<String, Matrix<f32>>
+}
+
+impl Adam {
+    pub fn new(beta_1: f32, beta_2: f32, epsilon: f32) -> Adam {
+        Adam {
+            beta_1: beta_1,
+            beta_2: beta_2,
+            epsilon: epsilon,
+            weight_decay: 0.0,
+            decoupled_weight_decay: false,
+
+            step_count: 0,
+            first_moments: HashMap::new(),
+            second_moments: HashMap::new()
+        }
+    }
+
+    pub fn adamw(beta_1: f32, beta_2: f32, epsilon: f32, weight_decay: f32) -> Adam {
+        let mut optimizer: Adam = Adam::new(beta_1, beta_2, epsilon);
+
+        optimizer.weight_decay = weight_decay;
+        optimizer.decoupled_weight_decay = true;
+        optimizer
+    }
+}
+
+impl Optimizer for Adam {
+    fn step(&mut self, layer: &mut dyn Layer, learning_rate: f32) {
+        self.step_count += 1;
+
+        let first_correction: f32 = 1.0 - self.beta_1.powi(self.step_count);
+        let second_correction: f32 = 1.0 - self.beta_2.powi(self.step_count);
+
+        for (name, parameter) in layer.parameters_mut() {
+            let mut data = parameter.borrow_mut();
+
+            let mut gradient: Matrix<f32> = match data.gradient.clone() {
+                Some(gradient) => gradient,
+                None => continue
+            };
+
+            if !self.decoupled_weight_decay && self.weight_decay > 0.0 {
+                gradient = gradient + data.value.clone() * self.weight_decay;
+            }
+
+            let first_moment: Matrix<f32> = match self.first_moments.get(&name) {
+                Some(moment) => moment.clone() * self.beta_1 + gradient.clone() * (1.0 - self.beta_1),
+                None => gradient.clone() * (1.0 - self.beta_1)
+            };
+
+            let second_moment: Matrix<f32> = match self.second_moments.get(&name) {
+                Some(moment) => moment.clone() * self.beta_2 + gradient.element_mult(gradient.clone()) * (1.0 - self.beta_2),
+                None => gradient.element_mult(gradient.clone()) * (1.0 - self.beta_2)
+            };
+
+            let mut update: Matrix<f32> = Matrix::new(gradient.rows, gradient.cols, 0.0);
+
+            for i in 0..update.data.len() {
+                let corrected_first: f32 = first_moment.data[i] / first_correction;
+                let corrected_second: f32 = second_moment.data[i] / second_correction;
+
+                update.data[i] = corrected_first / (corrected_second.sqrt() + self.epsilon);
+            }
+
+            if self.decoupled_weight_decay && self.weight_decay > 0.0 {
+                update = update + data.value.clone() * self.weight_decay;
+            }
+
+            data.value = data.value.clone() - update * learning_rate;
+
+            self.first_moments.insert(name.clone(), first_moment);
+            self.second_moments.insert(name, second_moment);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::algorithms::fixtures::dense_with_gradients;
+    use crate::one_bit_llm::parts::Dense;
+
+    // A layer whose 2 x 3 weights have gradients of mixed sign and very different sizes.
+    fn layer_with_gradients() -> (Dense, Matrix<f32>) {
+        let gradient: Matrix<f32> = Matrix { rows: 2, cols: 3, data: vec![0.5, -2.0, 1e-3, -1e-2, 30.0, -0.25] };
+
+        (dense_with_gradients(2, 3, &gradient.data, &[0.0; 3]), gradient)
+    }
+
+    #[test]
+    fn adam_first_step_moves_each_weight_by_the_learning_rate() {
+        let (mut layer, gradient) = layer_with_gradients();
+        let before: Matrix<f32> = layer.weights.value();
+
+        Adam::new(DEFAULT_BETA_1, DEFAULT_BETA_2, DEFAULT_EPSILON).step(&mut layer, 0.01);
+        let after: Matrix<f32> = layer.weights.value();
+
+        // With bias correction the first update is m_hat / sqrt(v_hat) = g / |g|, whatever the gradient's size.
+        for i in 0..gradient.data.len() {
+            let expected: f32 = before.data[i] - 0.01 * gradient.data[i].signum();
+            assert!((after.data[i] - expected).abs() < 1e-6, "weight {}: expected {}, got {}", i, expected, after.data[i]);
+        }
+    }
+
+    #[test]
+    fn adamw_first_step_adds_decoupled_weight_decay() {
+        let (mut layer, gradient) = layer_with_gradients();
+        let before: Matrix<f32> = layer.weights.value();
+
+        Adam::adamw(DEFAULT_BETA_1, DEFAULT_BETA_2, DEFAULT_EPSILON, 0.1).step(&mut layer, 0.01);
+        let after: Matrix<f32> = layer.weights.value();
+
+        for i in 0..gradient.data.len() {
+            let expected: f32 = before.data[i] - 0.01 * (gradient.data[i].signum() + 0.1 * before.data[i]);
+            assert!((after.data[i] - expected).abs() < 1e-6, "weight {}: expected {}, got {}", i, expected, after.data[i]);
+        }
+    }
+
+    #[test]
+    fn sgd_momentum_adds_the_previous_velocity() {
+        let (mut layer, gradient) = layer_with_gradients();
+        let before: Matrix<f32> = layer.weights.value();
+        let mut optimizer: SGD = SGD::new(0.9);
+
+        // The gradient is left in place, so the second step sees the same gradient plus 0.9 of the first velocity.
+        optimizer.step(&mut layer, 0.1);
+        optimizer.step(&mut layer, 0.1);
+        let after: Matrix<f32> = layer.weights.value();
+
+        for i in 0..gradient.data.len() {
+            let expected: f32 = before.data[i] - 0.1 * gradient.data[i] * (1.0 + 1.9);
+            assert!((after.data[i] - expected).abs() < 1e-4, "weight {}: expected {}, got {}", i, expected, after.data[i]);
+        }
+    }
+}
@@ -0,0 +1,228 @@
+use std::f32::consts::PI;
+
+// Defaults of `scheduler` in python/src/llm/train.py.
+pub const DEFAULT_LEARNING_RATE: f32 = 3e-5;
+pub const DEFAULT_MINIMUM_LEARNING_RATE: f32 = 5e-6;
+pub const DEFAULT_WARMUP_LEARNING_RATE: f32 = 1e-5;
+pub const DEFAULT_WARMUP_FRACTION: f32 = 0.1;
+
+// The learning rate to use at each optimizer step, counted from zero.
+pub trait LrSchedule {
+    fn learning_rate(&self, step: usize) -> f32;
+}
+
+
+// Linear interpolation from `start` to `end` as `step` goes from 0 to `steps`.
+fn linear(start: f32, end: f32, step: usize, steps: usize) -> f32 {
+    if steps == 0 {
+        return end;
+    }
+
+    start + (end - start) * (step as f32 / steps as f32)
+}
+
+
+pub struct ConstantSchedule {
+    pub learning_rate: f32
+}
+
+impl ConstantSchedule {
+    pub fn new(learning_rate: f32) -> ConstantSchedule {
+        ConstantSchedule {
+            learning_rate: learning_rate
+        }
+    }
+}
+
+impl LrSchedule for ConstantSchedule {
+    fn learning_rate(&self, _step: usize) -> f32 {
+        self.learning_rate
+    }
+}
+
+
+// Rises linearly from `warmup_learning_rate` to `learning_rate` over `warmup_steps`, then stays constant.
+pub struct LinearWarmupSchedule {
+    pub learning_rate: f32,
+    pub warmup_learning_rate: f32,
+    pub warmup_steps: usize
+}
+
+impl LinearWarmupSchedule {
+    pub fn new(learning_rate: f32, warmup_learning_rate: f32, warmup_steps: usize) -> LinearWarmupSchedule {
+        LinearWarmupSchedule {
+            learning_rate: learning_rate,
+            warmup_learning_rate: warmup_learning_rate,
+            warmup_steps: warmup_steps
+        }
+    }
+}
+
+impl LrSchedule for LinearWarmupSchedule {
+    fn learning_rate(&self, step: usize) -> f32 {
+        if step < self.warmup_steps {
+            return linear(self.warmup_learning_rate, self.learning_rate, step, self.warmup_steps);
+        }
+
+        self.learning_rate
+    }
+}
+
+
+// Port of the Python trainer's `scheduler`: linear warmup from `warmup_learning_rate` to `learning_rate`, then
+// cosine decay down to `minimum_learning_rate` at `total_steps`. The Python version adds the cosine term to
+// `learning_rate` instead of `minimum_learning_rate`, so it decays from 2 * learning_rate - minimum down to
+// learning_rate. This follows the intended curve from learning_rate down to the minimum.
+pub struct CosineSchedule {
+    pub learning_rate: f32,
+    pub minimum_learning_rate: f32,
+    pub warmup_learning_rate: f32,
+    pub warmup_steps: usize,
+    pub total_steps: usize
+}
+
+impl CosineSchedule {
+    pub fn new(learning_rate: f32, minimum_learning_rate: f32, warmup_learning_rate: f32, warmup_steps: usize, total_steps: usize) -> CosineSchedule {
+        CosineSchedule {
+            learning_rate: learning_rate,
+            minimum_learning_rate: minimum_learning_rate,
+            warmup_learning_rate: warmup_learning_rate,
+            warmup_steps: warmup_steps,
+            total_steps: total_steps
+        }
+    }
+
+    // The Python trainer's settings: its default rates, with the first 10% of all steps spent warming up.
+    pub fn with_defaults(total_steps: usize) -> CosineSchedule {
+        let warmup_steps: usize = (DEFAULT_WARMUP_FRACTION * total_steps as f32).ceil() as usize;
+
+        CosineSchedule::new(DEFAULT_LEARNING_RATE, DEFAULT_MINIMUM_LEARNING_RATE, DEFAULT_WARMUP_LEARNING_RATE, warmup_steps, total_steps)
+    }
+}
+
+impl LrSchedule for CosineSchedule {
+    fn learning_rate(&self, step: usize) -> f32 {
+        if step < self.warmup_steps {
+            return linear(self.warmup_learning_rate, self.learning_rate, step, self.warmup_steps);
+        }
+
+        let decay_steps: usize = self.total_steps.saturating_sub(self.warmup_steps).max(1);
+        let progress: f32 = ((step - self.warmup_steps) as f32 / decay_steps as f32).min(1.0);
+
+        self.minimum_learning_rate + (self.learning_rate - self.minimum_learning_rate) * (1.0 + (PI * progress).cos()) / 2.0
+    }
+}
+
+
+// Multiplies the learning rate by `gamma` every `step_size` steps.
+pub struct StepSchedule {
+    pub learning_rate: f32,
+    pub step_size: usize,
+    pub gamma: f32
+}
+
+impl StepSchedule {
+    pub fn new(learning_rate: f32, step_size: usize, gamma: f32) -> StepSchedule {
+        StepSchedule {
+            learning_rate: learning_rate,
+            step_size: step_size,
+            gamma: gamma
+        }
+    }
+}
+
+impl LrSchedule for StepSchedule {
+    fn learning_rate(&self, step: usize) -> f32 {
+        self.learning_rate * self.gamma.powi((step / self.step_size.max(1)) as i32)
+    }
+}
+
+
+// Linear warmup from zero to `learning_rate`, then decay proportional to 1 / sqrt(step), as in
+// "Attention Is All You Need". The two pieces meet at `warmup_steps`.
+pub struct InverseSqrtSchedule {
+    pub learning_rate: f32,
+    pub warmup_steps: usize
+}
+
+impl InverseSqrtSchedule {
+    pub fn new(learning_rate: f32, warmup_steps: usize) -> InverseSqrtSchedule {
+        InverseSqrtSchedule {
+            learning_rate: learning_rate,
+            warmup_steps: warmup_steps
+        }
+    }
+}
+
+impl LrSchedule for InverseSqrtSchedule {
+    fn learning_rate(&self, step: usize) -> f32 {
+        // Steps are counted from one here so the first step has a non-zero rate.
+        let step: usize = step + 1;
+
+        if step < self.warmup_steps {
+            return self.learning_rate * step as f32 / self.warmup_steps as f32;
+        }
+
+        self.learning_rate * (self.warmup_steps.max(1) as f32 / step as f32).sqrt()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!((actual - expected).abs() <= 1e-5 * expected.abs(), "expected {}, got {}", expected, actual);
+    }
+
+    #[test]
+    fn linear_warmup_reaches_the_peak_at_the_boundary() {
+        let schedule: LinearWarmupSchedule = LinearWarmupSchedule::new(1e-3, 1e-4, 10);
+
+        assert_close(schedule.learning_rate(0), 1e-4);
+        assert_close(schedule.learning_rate(5), 5.5e-4);
+        assert_close(schedule.learning_rate(10), 1e-3);
+        assert_close(schedule.learning_rate(1000), 1e-3);
+    }
+
+    #[test]
+    fn cosine_peaks_after_warmup_and_ends_at_the_minimum() {
+        let schedule: CosineSchedule = CosineSchedule::new(1e-3, 1e-5, 1e-4, 10, 110);
+
+        assert_close(schedule.learning_rate(0), 1e-4);
+        assert_close(schedule.learning_rate(9), 1e-4 + 9e-4 * 0.9);
+        assert_close(schedule.learning_rate(10), 1e-3);
+        // Half way through the decay the cosine term is zero.
+        assert_close(schedule.learning_rate(60), (1e-3 + 1e-5) / 2.0);
+        assert_close(schedule.learning_rate(110), 1e-5);
+        assert_close(schedule.learning_rate(500), 1e-5);
+    }
+
+    #[test]
+    fn cosine_defaults_warm_up_for_a_tenth_of_the_steps() {
+        let schedule: CosineSchedule = CosineSchedule::with_defaults(1000);
+
+        assert_eq!(schedule.warmup_steps, 100);
+        assert_close(schedule.learning_rate(100), DEFAULT_LEARNING_RATE);
+        assert_close(schedule.learning_rate(1000), DEFAULT_MINIMUM_LEARNING_RATE);
+    }
+
+    #[test]
+    fn step_schedule_decays_at_each_step_size() {
+        let schedule: StepSchedule = StepSchedule::new(1.0, 5, 0.5);
+
+        assert_close(schedule.learning_rate(4), 1.0);
+        assert_close(schedule.learning_rate(5), 0.5);
+        assert_close(schedule.learning_rate(12), 0.25);
+    }
+
+    #[test]
+    fn inverse_sqrt_meets_the_peak_at_the_end_of_warmup() {
+        let schedule: InverseSqrtSchedule = InverseSqrtSchedule::new(1e-3, 4);
+
+        assert_close(schedule.learning_rate(0), 2.5e-4);
+        assert_close(schedule.learning_rate(3), 1e-3);
+        assert_close(schedule.learning_rate(15), 5e-4);
+    }
+}
@@ -1,100 +1,131 @@
-use std::{fs::File, io::{Read, Seek, SeekFrom}};
-use nalgebra::*;
-
-use crate::one_bit_llm::parts::{FFN, Layer};
-
-pub fn load_tokens() -> Vec<u8> {
-    // Commented for testing purposes. The below code block only extracts the first 16 MB of data.
-    // let data = fs::read("../../dataset/tokens.bin").expect("Failed to read file.");
-
-    let mut input_file = File::open("../../dataset/tokens.bin").expect("Failed to read file.");
-    let mut data: Vec<u8> = vec![0; 16000];
-    let _ = input_file.seek(SeekFrom::Start(0));
-    let _ = input_file.read_exact(&mut data);
+use std::{fs::File, io::{self, Read}};
+use crate::algorithms::clipping::{clip_gradients_by_global_norm, clip_gradients_by_value, global_gradient_norm};
+use crate::algorithms::config::TrainConfig;
+use crate::algorithms::losses::{Loss, Objective};
+use crate::algorithms::optimizers::Optimizer;
+use crate::algorithms::schedules::LrSchedule;
+use crate::matrix::matrix::Matrix;
+use crate::one_bit_llm::checkpoint::save_checkpoint;
+use crate::one_bit_llm::parts::{LLM, Layer};
+
+// Reads the raw bytes of the first `token_limit` tokens of `path`, or the whole file when `token_limit` is 0.
+pub fn load_tokens(path: &str, token_limit: usize) -> io::Result<Vec<u8>> {
+    let mut input_file = File::open(path)?;
+    let mut data: Vec<u8> = vec![];
+
+    if token_limit == 0 {
+        input_file.read_to_end(&mut data)?;
+    } else {
+        input_file.take(4 * token_limit as u64).read_to_end(&mut data)?;
+    }
 
-    data
+    Ok(data)
 }
 
 pub fn convert_to_usize(input: Vec<u8>) -> Vec<usize> {
     let mut processed_data: Vec<usize> = vec![];
 
+    // tokens.bin is written by numpy as native (little-endian) uint32 values.
     for chunk in input.chunks_exact(4) {
-        processed_data.push((chunk[0] as usize) * 256^3 + (chunk[1] as usize) * 256^2 + (chunk[2] as usize) * 256 + (chunk[3] as usize));
+        processed_data.push(u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]) as usize);
     }
 
     processed_data
 }
 
-pub fn one_hot_encoding(data: Vec<usize>) -> DMatrix<f64> {
-    let mut result: DMatrix<f64> = DMatrix::from_element(*data.iter().max().unwrap() + 1, data.len(), 0.0);
+pub fn train(mut model: LLM, training_data: Vec<usize>, config: &TrainConfig) {
+    let sequence_length: usize = config.sequence_length;
+    let batch_size: usize = config.batch_size;
 
-    for (index, element) in data.iter().enumerate() {
-        result[(*element, index)] = 1.0;
+    if batch_size == 0 {
+        println!("Train Error - The batch size must be at least 1.");
+        return;
     }
 
-    result
-}
-
-pub fn train(mut model: FFN, training_data: Vec<usize>) {
-    // Constants that can be edited to vary the training process.
-    const EPOCH_COUNT: usize = 100;
-    const BATCH_COUNT_PER_EPOCH: usize = 64;
-    const LEARNING_RATE: f64 = 0.0002;
-    const SEQUENCE_LENGTH: usize = 256;
+    // Each sequence reads one token past its inputs for the last target.
+    if sequence_length == 0 || sequence_length + 1 > training_data.len() {
+        println!("Train Error - Sequence length {} does not fit {} tokens of training data.", sequence_length, training_data.len());
+        return;
+    }
 
-    let vocabulary_size = *training_data.iter().max().unwrap();
+    let vocabulary_size: usize = model.config.vocabulary_size;
+    let objective: Objective = config.objective();
+    let loss_function: Box<dyn Loss> = objective.loss_function(config.z_loss_coefficient);
+    let mut optimizer: Box<dyn Optimizer> = config.optimizer();
+    let schedule: Box<dyn LrSchedule> = config.schedule();
+    let mut global_step: usize = 0;
 
     // Batch processing
     let mut current_batch_index: usize = 0;
 
-    fn generate_batch (current_batch_index: usize, training_data: &Vec<usize>) -> (DMatrix<usize>, usize, usize) {
-        let mut target_index = current_batch_index + SEQUENCE_LENGTH;
+    // Returns `batch_size` consecutive sequences, each as `sequence_length` input tokens and the same tokens shifted one
+    // ahead as the targets, and the next start index. As with the Python `TokenDataset`, each sequence reads
+    // sequence_length + 1 tokens and the next one starts `sequence_length` tokens later, so every token is predicted once.
+    // Reading wraps back to the start of the data when a sequence would run past the end.
+    fn generate_batch (current_batch_index: usize, training_data: &[usize], sequence_length: usize, batch_size: usize) -> (Vec<Vec<usize>>, Vec<Vec<usize>>, usize) {
+        let mut start_index = current_batch_index;
+        let mut inputs: Vec<Vec<usize>> = vec![];
+        let mut targets: Vec<Vec<usize>> = vec![];
 
-        if target_index >= training_data.len() {
-            target_index -= training_data.len();
-        }
+        for _ in 0..batch_size {
+            if start_index + sequence_length + 1 > training_data.len() {
+                start_index = 0;
+            }
 
-        let mut extracted_data: Vec<usize> = vec![0; SEQUENCE_LENGTH];
-        
-        for i in 0..(SEQUENCE_LENGTH - 1) {
-            //print!("{}, {}, {}", target_index, i, SEQUENCE_LENGTH);
-            extracted_data[i] = training_data[target_index + i - SEQUENCE_LENGTH];
-        }
+            inputs.push(training_data[start_index..(start_index + sequence_length)].to_vec());
+            targets.push(training_data[(start_index + 1)..(start_index + sequence_length + 1)].to_vec());
 
-        let mut encoded_data: DMatrix<usize> = DMatrix::from_element(1, training_data.len() - 1, 0);
-
-        for i in 0..(training_data.len() - 1) {
-            encoded_data[(0, i)] = training_data[i];
+            start_index += sequence_length;
         }
 
-        (encoded_data, training_data[training_data.len() - 1], target_index)
+        (inputs, targets, start_index)
     }
 
     // Training algorithm.
-    for epoch in 0..EPOCH_COUNT {
-        let mut epoch_loss: f64 = 0.0;
-        for _ in 0..BATCH_COUNT_PER_EPOCH {
-            let batch_info = generate_batch(current_batch_index, &training_data);
+    for epoch in 0..config.epoch_count {
+        let mut epoch_loss: f32 = 0.0;
+        for _ in 0..config.batches_per_epoch {
+            let batch_info = generate_batch(current_batch_index, &training_data, sequence_length, batch_size);
             current_batch_index = batch_info.2;
 
-            let model_result: DMatrix<f64> = model.compute(batch_info.0, true);
-            
-            let mut target: DMatrix<f64> = DMatrix::from_element(1, vocabulary_size, 0.0);
-            let mut loss_gradients: DMatrix<f64> = DMatrix::from_element(1, vocabulary_size, 1.0);
+            model.zero_grad();
+
+            // The whole batch goes forward and backward once as (batch_size * sequence_length) rows, so the loss and
+            // its gradients are averaged over every token in the batch.
+            let model_result: Matrix<f32> = model.forward_batch(&batch_info.0);
+            let (loss, loss_gradients) = loss_function.compute(&model_result, &objective.targets(&batch_info.1.concat(), vocabulary_size));
+
+            model.calculate_gradients(vec![loss_gradients]);
+
+            epoch_loss += loss;
 
-            target[(0, batch_info.1)] = 1.0;
-            loss_gradients[(0, batch_info.1)] = -1.0;
+            // The norm is logged before any clipping, so spikes stay visible.
+            let gradient_norm: f32 = if config.gradient_clip_norm > 0.0 {
+                clip_gradients_by_global_norm(&mut model, config.gradient_clip_norm)
+            } else {
+                global_gradient_norm(&model)
+            };
 
-            let loss: DMatrix<f64> = (target - model_result).abs();
-            epoch_loss += loss.sum();
+            if config.gradient_clip_value > 0.0 {
+                clip_gradients_by_value(&mut model, config.gradient_clip_value);
+            }
 
-            model.calculate_gradients(loss_gradients);
-            model.adjust_parameters(LEARNING_RATE);
+            let learning_rate: f32 = schedule.learning_rate(global_step);
+            optimizer.step(&mut model, learning_rate);
+
+            global_step += 1;
+
+            if global_step.is_multiple_of(config.log_every) {
+                println!("Step {}: loss {:.4}, learning rate {:e}, gradient norm {:.4}", global_step, loss, learning_rate, gradient_norm);
+            }
         }
 
-        epoch_loss /= BATCH_COUNT_PER_EPOCH as f64;
+        epoch_loss /= config.batches_per_epoch as f32;
 
-        print!("Epoch {} complete.", epoch);
-        print!("Loss: {}", epoch_loss)
+        println!("Epoch {} complete. Loss: {}", epoch, epoch_loss);
+
+        if !config.checkpoint_path.is_empty() && let Err(error) = save_checkpoint(&model, &config.checkpoint_path) {
+            println!("Train Error - Could not save a checkpoint to {}: {}", config.checkpoint_path, error);
+        }
     }
 }
\ No newline at end of file
@@ -1,19 +1,51 @@
+use std::env;
+
 use crate::matrix::matrix::Matrix;
 
 pub mod one_bit_llm;
 pub mod algorithms;
 pub mod matrix;
 
-//use crate::{algorithms::train::train, one_bit_llm::parts::LLM};
+use crate::{algorithms::{config::TrainConfig, train::train}, one_bit_llm::parts::LLM};
 
 fn main() {
-    /*
-    let data: Vec<u8> = algorithms::train::load_tokens();
-    let data_converted: Vec<usize> = algorithms::train::convert_to_usize(data.clone());
-    
-    let model: LLM = LLM::new(*data_converted.iter().max().unwrap(), 512, 8, 1024, 6, 255);
-    train(model, data_converted);
-    */
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    // `train [--config <file>] [--<setting> <value>]...` trains a model. See `TrainConfig` for the settings.
+    if args.first().map(|command| command.as_str()) == Some("train") {
+        let config: TrainConfig = match TrainConfig::from_args(&args[1..]) {
+            Ok(config) => config,
+            Err(error) => {
+                println!("Config Error - {}", error);
+                return;
+            }
+        };
+
+        let data: Vec<u8> = match algorithms::train::load_tokens(&config.data_path, config.token_limit) {
+            Ok(data) => data,
+            Err(error) => {
+                println!("Data Error - Could not read {}: {}", config.data_path, error);
+                return;
+            }
+        };
+        let data_converted: Vec<usize> = algorithms::train::convert_to_usize(data);
+
+        match data_converted.iter().max() {
+            Some(&token) if token >= config.vocabulary_size => {
+                println!("Train Error - {} holds token {}, outside the vocabulary of {}.", config.data_path, token, config.vocabulary_size);
+                return;
+            },
+            Some(_) => {},
+            None => {
+                println!("Train Error - {} holds no tokens to train on.", config.data_path);
+                return;
+            }
+        }
+
+        let model: LLM = LLM::from_config(config.model_config());
+        train(model, data_converted, &config);
+        return;
+    }
 
     let mut matrix_1: Matrix<f32> = Matrix::new(5, 3, 3.0);
 
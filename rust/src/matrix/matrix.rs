@@ -90,9 +90,141 @@ impl<T: Numeric> Matrix<T> {
     pub fn transpose(&self) -> Matrix<T> {
         let mut data: Vec<T> = vec![];
 
+        for col in 0..self.cols {
+            for row in 0..self.rows {
+                data.push(self.get(row, col));
+            }
+        }
+
+        Matrix {
+            rows: self.cols,
+            cols: self.rows,
+            data: data
+        }
+    }
+
+    pub fn slice_cols(&self, start: usize, end: usize) -> Matrix<T> {
+        if start > end || end > self.cols {
+            println!("ERROR ENCOUNTERED - Slicing matrix columns.");
+            println!("Invalid range: {}..{} for ({}, {})", start, end, self.rows, self.cols);
+            return self.clone(); // Fallback option.
+        }
+
+        let mut data: Vec<T> = vec![];
+
+        for row in 0..self.rows {
+            for col in start..end {
+                data.push(self.get(row, col));
+            }
+        }
+
+        Matrix {
+            rows: self.rows,
+            cols: end - start,
+            data: data
+        }
+    }
+
+    pub fn slice_rows(&self, start: usize, end: usize) -> Matrix<T> {
+        if start > end || end > self.rows {
+            println!("ERROR ENCOUNTERED - Slicing matrix rows.");
+            println!("Invalid range: {}..{} for ({}, {})", start, end, self.rows, self.cols);
+            return self.clone(); // Fallback option.
+        }
+
+        Matrix {
+            rows: end - start,
+            cols: self.cols,
+            data: self.data[start * self.cols..end * self.cols].to_vec()
+        }
+    }
+
+    pub fn concat_cols(matrices: Vec<Matrix<T>>) -> Matrix<T> {
+        let rows: usize = matrices[0].rows;
+        let cols: usize = matrices.iter().map(|matrix| matrix.cols).sum();
+
+        if matrices.iter().any(|matrix| matrix.rows != rows) {
+            println!("ERROR ENCOUNTERED - Concatenating matrix columns.");
+            println!("Row count mismatch: expected {} rows in every matrix.", rows);
+            return matrices[0].clone(); // Fallback option.
+        }
+
+        let mut data: Vec<T> = vec![];
+
+        for row in 0..rows {
+            for matrix in matrices.iter() {
+                for col in 0..matrix.cols {
+                    data.push(matrix.get(row, col));
+                }
+            }
+        }
+
+        Matrix {
+            rows: rows,
+            cols: cols,
+            data: data
+        }
+    }
+
+    pub fn concat_rows(matrices: Vec<Matrix<T>>) -> Matrix<T> {
+        let cols: usize = matrices[0].cols;
+        let rows: usize = matrices.iter().map(|matrix| matrix.rows).sum();
+
+        if matrices.iter().any(|matrix| matrix.cols != cols) {
+            println!("ERROR ENCOUNTERED - Concatenating matrix rows.");
+            println!("Column count mismatch: expected {} columns in every matrix.", cols);
+            return matrices[0].clone(); // Fallback option.
+        }
+
+        let mut data: Vec<T> = vec![];
+
+        for matrix in matrices.iter() {
+            data.extend(matrix.data.iter().cloned());
+        }
+
+        Matrix {
+            rows: rows,
+            cols: cols,
+            data: data
+        }
+    }
+
+
+    // Broadcasting Functions
+    pub fn add_row_vector(&self, other: Matrix<T>) -> Matrix<T> {
+        if other.rows != 1 || self.cols != other.cols {
+            println!("ERROR ENCOUNTERED - Broadcasting a row vector across a matrix.");
+            println!("Dimension mismatch: ({}, {}) + ({}, {})", self.rows, self.cols, other.rows, other.cols);
+            return self.clone(); // Fallback option.
+        }
+
+        let mut data: Vec<T> = vec![];
+
+        for row in 0..self.rows {
+            for col in 0..self.cols {
+                data.push(self.get(row, col) + other.get(0, col));
+            }
+        }
+
+        Matrix {
+            rows: self.rows,
+            cols: self.cols,
+            data: data
+        }
+    }
+
+    pub fn mult_row_vector(&self, other: Matrix<T>) -> Matrix<T> {
+        if other.rows != 1 || self.cols != other.cols {
+            println!("ERROR ENCOUNTERED - Broadcasting a row vector across a matrix.");
+            println!("Dimension mismatch: ({}, {}) * ({}, {})", self.rows, self.cols, other.rows, other.cols);
+            return self.clone(); // Fallback option.
+        }
+
+        let mut data: Vec<T> = vec![];
+
         for row in 0..self.rows {
             for col in 0..self.cols {
-                data.push(self.get(col, row));
+                data.push(self.get(row, col) * other.get(0, col));
             }
         }
 
@@ -103,6 +235,18 @@ impl<T: Numeric> Matrix<T> {
         }
     }
 
+    pub fn col_sums(&self) -> Matrix<T> {
+        let mut result: Matrix<T> = Matrix::new(1, self.cols, T::default());
+
+        for row in 0..self.rows {
+            for col in 0..self.cols {
+                result.set(0, col, result.get(0, col) + self.get(row, col));
+            }
+        }
+
+        result
+    }
+
 
     // Element-wise Functions
     pub fn element_mult(&self, other: Matrix<T>) -> Matrix<T> {
@@ -129,6 +273,22 @@ impl<T: Numeric> Matrix<T> {
         }
     }
 
+    // Applies `function` to every element.
+    pub fn apply(&self, function: fn(T) -> T) -> Matrix<T> {
+        let mut data: Vec<T> = vec![];
+
+        for i in 0..self.data.len() {
+            data.push(function(self.data[i].clone()));
+        }
+
+        Matrix {
+            rows: self.rows,
+            cols: self.cols,
+            data: data
+        }
+    }
+
+
     // Standard Trigonometric Functions
     pub fn sin(&self) -> Matrix<T> {
         let mut data: Vec<T> = vec![];
@@ -242,14 +402,21 @@ impl<T: Numeric> Matrix<T> {
         let mut data: Vec<T> = vec![];
 
         for row in 0..self.rows {
+            // Subtracting the row maximum keeps exp() finite, including for rows filled with large additive masks.
+            let mut maximum: T = T::neg_infinity();
+
+            for col in 0..self.cols {
+                maximum = maximum.max(self.get(row, col));
+            }
+
             let mut total: T = T::default();
 
             for col in 0..self.cols {
-                total = total + self.get(row, col).exp();
+                total = total + (self.get(row, col) - maximum).exp();
             }
 
             for col in 0..self.cols {
-                data.push(self.get(row, col).clone().exp() / total);
+                data.push((self.get(row, col) - maximum).exp() / total);
             }
         }
 
@@ -0,0 +1,380 @@
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+
+use crate::matrix::matrix::Matrix;
+use crate::one_bit_llm::parts::{Activation, FeedForward, LLM, LLMConfig, Layer, Normalization, PositionEncoding};
+
+// Checkpoints are little-endian binary files: a magic number and version, the model config, and then every
+// named parameter as (name, rows, cols, values). The model is rebuilt from the stored config before the
+// parameters are loaded, so settings that change parameter shapes (such as the key/value head count) always
+// match the weights they were trained with.
+const MAGIC: &[u8; 4] = b"OBLM";
+const VERSION: u32 = 3;
+
+
+struct Reader {
+    bytes: Vec<u8>,
+    position: usize
+}
+
+impl Reader {
+    fn take(&mut self, count: usize) -> Result<&[u8]> {
+        if self.position + count > self.bytes.len() {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "Checkpoint ended unexpectedly."));
+        }
+
+        let slice: &[u8] = &self.bytes[self.position..self.position + count];
+        self.position += count;
+
+        Ok(slice)
+    }
+
+    fn read_u32(&mut self) -> Result<u32> {
+        let bytes: &[u8] = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn read_u64(&mut self) -> Result<u64> {
+        let bytes: &[u8] = self.take(8)?;
+        Ok(u64::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7]]))
+    }
+
+    fn read_f32(&mut self) -> Result<f32> {
+        Ok(f32::from_bits(self.read_u32()?))
+    }
+
+    fn read_usize(&mut self) -> Result<usize> {
+        Ok(self.read_u32()? as usize)
+    }
+}
+
+fn invalid_data(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+
+fn write_u32(bytes: &mut Vec<u8>, value: u32) {
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_f32(bytes: &mut Vec<u8>, value: f32) {
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+fn activation_tag(activation: Activation) -> u32 {
+    match activation {
+        Activation::GELU => 0,
+        Activation::ExactGELU => 1,
+        Activation::ReLU => 2,
+        Activation::SquaredReLU => 3,
+        Activation::SiLU => 4
+    }
+}
+
+fn activation_from_tag(tag: u32) -> Result<Activation> {
+    match tag {
+        0 => Ok(Activation::GELU),
+        1 => Ok(Activation::ExactGELU),
+        2 => Ok(Activation::ReLU),
+        3 => Ok(Activation::SquaredReLU),
+        4 => Ok(Activation::SiLU),
+        _ => Err(invalid_data(format!("Unknown activation tag {}.", tag)))
+    }
+}
+
+fn write_config(bytes: &mut Vec<u8>, config: &LLMConfig) {
+    write_u32(bytes, config.vocabulary_size as u32);
+    write_u32(bytes, config.d_model as u32);
+    write_u32(bytes, config.head_count as u32);
+    write_u32(bytes, config.kv_head_count as u32);
+    write_u32(bytes, config.ffn_inner_size as u32);
+    write_u32(bytes, config.decoder_count as u32);
+    write_u32(bytes, config.max_positions as u32);
+
+    match config.position_encoding {
+        PositionEncoding::Learned => { write_u32(bytes, 0); write_f32(bytes, 0.0); },
+        PositionEncoding::Rotary { base } => { write_u32(bytes, 1); write_f32(bytes, base); },
+        PositionEncoding::ALiBi => { write_u32(bytes, 2); write_f32(bytes, 0.0); }
+    }
+
+    write_u32(bytes, match config.normalization {
+        Normalization::LayerNorm => 0,
+        Normalization::RMSNorm => 1
+    });
+
+    match config.feed_forward {
+        FeedForward::Standard(activation) => { write_u32(bytes, 0); write_u32(bytes, activation_tag(activation)); },
+        FeedForward::SwiGLU => { write_u32(bytes, 1); write_u32(bytes, 0); }
+    }
+
+    // Zero means no sliding window, and untiled attention.
+    write_u32(bytes, config.attention_window.unwrap_or(0) as u32);
+    write_u32(bytes, config.attention_tile_size.unwrap_or(0) as u32);
+
+    write_f32(bytes, config.attention_dropout);
+    write_f32(bytes, config.residual_dropout);
+    bytes.extend_from_slice(&config.dropout_seed.to_le_bytes());
+
+    write_f32(bytes, config.parameter_min);
+    write_f32(bytes, config.parameter_max);
+}
+
+fn read_config(reader: &mut Reader) -> Result<LLMConfig> {
+    let vocabulary_size: usize = reader.read_usize()?;
+    let d_model: usize = reader.read_usize()?;
+    let head_count: usize = reader.read_usize()?;
+    let kv_head_count: usize = reader.read_usize()?;
+    let ffn_inner_size: usize = reader.read_usize()?;
+    let decoder_count: usize = reader.read_usize()?;
+    let max_positions: usize = reader.read_usize()?;
+
+    let mut config: LLMConfig = LLMConfig::new(vocabulary_size, d_model, head_count, ffn_inner_size, decoder_count, max_positions);
+
+    if head_count == 0 || !d_model.is_multiple_of(head_count) {
+        return Err(invalid_data(format!("d_model {} is not divisible by the head count {}.", d_model, head_count)));
+    }
+
+    if kv_head_count == 0 || !head_count.is_multiple_of(kv_head_count) {
+        return Err(invalid_data(format!("Head count {} is not divisible by the key/value head count {}.", head_count, kv_head_count)));
+    }
+
+    config.kv_head_count = kv_head_count;
+
+    let position_tag: u32 = reader.read_u32()?;
+    let rotary_base: f32 = reader.read_f32()?;
+
+    config.position_encoding = match position_tag {
+        0 => PositionEncoding::Learned,
+        1 => PositionEncoding::Rotary { base: rotary_base },
+        2 => PositionEncoding::ALiBi,
+        _ => return Err(invalid_data(format!("Unknown position encoding tag {}.", position_tag)))
+    };
+
+    config.normalization = match reader.read_u32()? {
+        0 => Normalization::LayerNorm,
+        1 => Normalization::RMSNorm,
+        tag => return Err(invalid_data(format!("Unknown normalization tag {}.", tag)))
+    };
+
+    let feed_forward_tag: u32 = reader.read_u32()?;
+    let activation: Activation = activation_from_tag(reader.read_u32()?)?;
+
+    config.feed_forward = match feed_forward_tag {
+        0 => FeedForward::Standard(activation),
+        1 => FeedForward::SwiGLU,
+        _ => return Err(invalid_data(format!("Unknown feed forward tag {}.", feed_forward_tag)))
+    };
+
+    config.attention_window = match reader.read_usize()? {
+        0 => None,
+        window => Some(window)
+    };
+
+    config.attention_tile_size = match reader.read_usize()? {
+        0 => None,
+        tile_size => Some(tile_size)
+    };
+
+    config.attention_dropout = reader.read_f32()?;
+    config.residual_dropout = reader.read_f32()?;
+
+    for rate in [config.attention_dropout, config.residual_dropout] {
+        if !(0.0..1.0).contains(&rate) {
+            return Err(invalid_data(format!("Dropout rate {} is outside [0, 1).", rate)));
+        }
+    }
+    config.dropout_seed = reader.read_u64()?;
+
+    config.parameter_min = reader.read_f32()?;
+    config.parameter_max = reader.read_f32()?;
+
+    Ok(config)
+}
+
+
+pub fn save_checkpoint(model: &LLM, path: &str) -> Result<()> {
+    let mut bytes: Vec<u8> = vec![];
+
+    bytes.extend_from_slice(MAGIC);
+    write_u32(&mut bytes, VERSION);
+    write_config(&mut bytes, &model.config);
+
+    let parameters = model.parameters();
+    write_u32(&mut bytes, parameters.len() as u32);
+
+    for (name, parameter) in parameters {
+        let value: Matrix<f32> = parameter.value();
+
+        write_u32(&mut bytes, name.len() as u32);
+        bytes.extend_from_slice(name.as_bytes());
+        write_u32(&mut bytes, value.rows as u32);
+        write_u32(&mut bytes, value.cols as u32);
+
+        for element in value.data.iter() {
+            write_f32(&mut bytes, *element);
+        }
+    }
+
+    fs::write(path, bytes)
+}
+
+// Rebuilds the model described by the checkpoint's config and loads its parameters. Every parameter of the
+// model must be present with the shape the config implies.
+pub fn load_checkpoint(path: &str) -> Result<LLM> {
+    let mut reader: Reader = Reader {
+        bytes: fs::read(path)?,
+        position: 0
+    };
+
+    if reader.take(4)? != MAGIC {
+        return Err(invalid_data(format!("{} is not a checkpoint.", path)));
+    }
+
+    let version: u32 = reader.read_u32()?;
+
+    if version != VERSION {
+        return Err(invalid_data(format!("Unsupported checkpoint version {}.", version)));
+    }
+
+    let config: LLMConfig = read_config(&mut reader)?;
+
+    // Building the model allocates every parameter, so a config implying more values than the file still holds is
+    // rejected first, before a corrupt size can exhaust memory.
+    let remaining_values: usize = (reader.bytes.len() - reader.position) / 4;
+
+    if config.parameter_count() > remaining_values as u128 {
+        return Err(invalid_data(format!("The config implies {} parameter values but the checkpoint holds at most {}.", config.parameter_count(), remaining_values)));
+    }
+
+    let mut model: LLM = LLM::from_config(config);
+    let parameter_count: usize = reader.read_usize()?;
+    let mut loaded: Vec<String> = vec![];
+
+    for _ in 0..parameter_count {
+        let name_length: usize = reader.read_usize()?;
+        let name: String = String::from_utf8(reader.take(name_length)?.to_vec()).map_err(|_| invalid_data("Parameter name is not UTF-8.".to_string()))?;
+        let rows: usize = reader.read_usize()?;
+        let cols: usize = reader.read_usize()?;
+
+        // The shape is checked against the model before anything is allocated, so a corrupt size cannot exhaust memory.
+        let mut parameters = model.parameters_mut();
+        let parameter = match parameters.iter_mut().find(|(parameter_name, _)| *parameter_name == name) {
+            Some((_, parameter)) => parameter,
+            None => return Err(invalid_data(format!("The model has no parameter named {}.", name)))
+        };
+
+        let mut data = parameter.borrow_mut();
+
+        if data.value.rows != rows || data.value.cols != cols {
+            return Err(invalid_data(format!("{} is ({} x {}) in the checkpoint but ({} x {}) in the model.", name, rows, cols, data.value.rows, data.value.cols)));
+        }
+
+        let mut value: Matrix<f32> = Matrix::new(rows, cols, 0.0);
+
+        for element in value.data.iter_mut() {
+            *element = reader.read_f32()?;
+        }
+
+        data.value = value;
+        loaded.push(name);
+    }
+
+    for (name, _) in model.parameters() {
+        if !loaded.contains(&name) {
+            return Err(invalid_data(format!("The checkpoint is missing {}.", name)));
+        }
+    }
+
+    Ok(model)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temporary_path(name: &str) -> String {
+        std::env::temp_dir().join(format!("onebitml_{}_{}.bin", name, std::process::id())).to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn corrupt_shape_is_rejected_before_allocating() {
+        let path: String = temporary_path("corrupt_shape");
+        let model: LLM = LLM::new(7, 8, 2, 16, 1, 8);
+        save_checkpoint(&model, &path).unwrap();
+
+        // Claim that the first parameter is u32::MAX by u32::MAX; its rows and cols follow its name.
+        let name: String = model.parameters()[0].0.clone();
+        let mut bytes: Vec<u8> = fs::read(&path).unwrap();
+        let name_position: usize = bytes.windows(name.len()).position(|window| window == name.as_bytes()).unwrap();
+        let rows_position: usize = name_position + name.len();
+
+        bytes[rows_position..rows_position + 8].copy_from_slice(&[0xFF; 8]);
+        fs::write(&path, bytes).unwrap();
+
+        let error: Error = load_checkpoint(&path).err().unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn corrupt_config_is_rejected_before_building_the_model() {
+        let path: String = temporary_path("corrupt_config");
+        save_checkpoint(&LLM::new(7, 8, 2, 16, 1, 8), &path).unwrap();
+
+        // The vocabulary size is the first config value, after the magic number and the version.
+        let mut bytes: Vec<u8> = fs::read(&path).unwrap();
+        bytes[8..12].copy_from_slice(&u32::MAX.to_le_bytes());
+        fs::write(&path, bytes).unwrap();
+
+        let error: Error = load_checkpoint(&path).err().unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn round_trip_restores_config_and_parameters_exactly() {
+        let mut config: LLMConfig = LLMConfig::new(9, 8, 4, 12, 2, 16);
+        config.kv_head_count = 2;
+        config.position_encoding = PositionEncoding::Rotary { base: 500.0 };
+        config.normalization = Normalization::RMSNorm;
+        config.feed_forward = FeedForward::SwiGLU;
+        config.attention_dropout = 0.25;
+        config.residual_dropout = 0.125;
+        config.dropout_seed = 42;
+        config.attention_window = Some(5);
+        config.attention_tile_size = Some(3);
+
+        let path: String = temporary_path("round_trip");
+        let mut model: LLM = LLM::from_config(config);
+        save_checkpoint(&model, &path).unwrap();
+
+        let mut loaded: LLM = load_checkpoint(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.config.kv_head_count, 2);
+        assert_eq!(loaded.config.position_encoding, PositionEncoding::Rotary { base: 500.0 });
+        assert_eq!(loaded.config.normalization, Normalization::RMSNorm);
+        assert_eq!(loaded.config.feed_forward, FeedForward::SwiGLU);
+        assert_eq!((loaded.config.attention_dropout, loaded.config.residual_dropout, loaded.config.dropout_seed), (0.25, 0.125, 42));
+        assert_eq!(loaded.config.attention_window, Some(5));
+        assert_eq!(loaded.config.attention_tile_size, Some(3));
+
+        let original_parameters = model.parameters();
+        let loaded_parameters = loaded.parameters();
+        assert_eq!(original_parameters.len(), loaded_parameters.len());
+
+        for ((original_name, original), (loaded_name, loaded)) in original_parameters.iter().zip(loaded_parameters.iter()) {
+            assert_eq!(original_name, loaded_name);
+            assert_eq!(original.value().data, loaded.value().data);
+        }
+
+        model.eval();
+        loaded.eval();
+
+        let tokens: [usize; 6] = [3, 1, 8, 0, 5, 2];
+        assert_eq!(model.forward_tokens(&tokens).data, loaded.forward_tokens(&tokens).data);
+    }
+}
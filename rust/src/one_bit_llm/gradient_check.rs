@@ -0,0 +1,298 @@
+use crate::matrix::matrix::Matrix;
+use crate::one_bit_llm::parts::{DEFAULT_NORM_EPSILON, DEFAULT_ROTARY_BASE, Activation, Decoder, Dense, Embedding, ExactGELU, FFN, FeedForward, GELU, LLM, LLMConfig, Layer, LayerNorm, MultiHeadAttention, Normalization, PositionEncoding, PositionalEmbedding, ReLU, RMSNorm, ScaledDotProduct, SiLU, SquaredReLU, SwiGLU, generate_parameter, seed_parameters};
+
+// Step used for the central finite differences. f32 rounding dominates below roughly 1e-3.
+pub const DEFAULT_STEP: f32 = 0.01;
+pub const DEFAULT_TOLERANCE: f32 = 0.01;
+const NORM_FLOOR: f32 = 0.01;
+
+pub struct GradientCheckResult {
+    pub name: String,
+    pub relative_error: f32,
+    pub max_absolute_error: f32
+}
+
+pub struct GradientCheckReport {
+    pub layer: String,
+    pub results: Vec<GradientCheckResult>
+}
+
+impl GradientCheckReport {
+    pub fn passed(&self, tolerance: f32) -> bool {
+        self.results.iter().all(|result| result.relative_error <= tolerance)
+    }
+
+    pub fn display(&self) {
+        println!("Gradient check: {}", self.layer);
+
+        for result in self.results.iter() {
+            println!("  {}: relative error {:e}, max absolute error {:e}", result.name, result.relative_error, result.max_absolute_error);
+        }
+    }
+}
+
+
+// The scalar loss is sum(output * projection), so d(loss)/d(output) is just the projection matrix.
+// It is summed in f64, so that rounding in the sum does not swamp the small differences between perturbed passes.
+fn projected_loss(output: &Matrix<f32>, projection: &Matrix<f32>) -> f64 {
+    let mut total: f64 = 0.0;
+
+    for i in 0..output.data.len() {
+        total += output.data[i] as f64 * projection.data[i] as f64;
+    }
+
+    total
+}
+
+// Relative error is measured over the whole tensor, ||a - n|| / (||a|| + ||n||), which stays meaningful
+// when individual entries are close to zero. The denominator is floored so that gradients which are zero
+// by construction (such as attention key biases) compare their finite difference noise absolutely.
+fn compare(name: String, analytic: Option<Matrix<f32>>, numeric: Matrix<f32>) -> GradientCheckResult {
+    let analytic: Matrix<f32> = match analytic {
+        Some(analytic) if analytic.rows == numeric.rows && analytic.cols == numeric.cols => analytic,
+        _ => {
+            return GradientCheckResult {
+                name: name,
+                relative_error: f32::INFINITY,
+                max_absolute_error: f32::INFINITY
+            };
+        }
+    };
+
+    let mut difference_norm: f32 = 0.0;
+    let mut analytic_norm: f32 = 0.0;
+    let mut numeric_norm: f32 = 0.0;
+    let mut max_absolute_error: f32 = 0.0;
+
+    for i in 0..numeric.data.len() {
+        let difference: f32 = analytic.data[i] - numeric.data[i];
+
+        difference_norm += difference * difference;
+        analytic_norm += analytic.data[i] * analytic.data[i];
+        numeric_norm += numeric.data[i] * numeric.data[i];
+        max_absolute_error = max_absolute_error.max(difference.abs());
+    }
+
+    let denominator: f32 = (analytic_norm.sqrt() + numeric_norm.sqrt()).max(NORM_FLOOR);
+
+    GradientCheckResult {
+        name: name,
+        relative_error: difference_norm.sqrt() / denominator,
+        max_absolute_error: max_absolute_error
+    }
+}
+
+
+// Compares `calculate_gradients` against central finite differences for every input and parameter.
+pub fn check_gradients(layer_name: &str, layer: &mut dyn Layer, inputs: Vec<Matrix<f32>>, step: f32) -> GradientCheckReport {
+    run_check(layer_name, layer, inputs, true, step)
+}
+
+// As `check_gradients`, but skips the inputs, for layers whose inputs are not differentiable (e.g. token indices).
+pub fn check_parameter_gradients(layer_name: &str, layer: &mut dyn Layer, inputs: Vec<Matrix<f32>>, step: f32) -> GradientCheckReport {
+    run_check(layer_name, layer, inputs, false, step)
+}
+
+fn run_check(layer_name: &str, layer: &mut dyn Layer, inputs: Vec<Matrix<f32>>, check_inputs: bool, step: f32) -> GradientCheckReport {
+    let was_training: bool = layer.is_training();
+
+    layer.set_training(true);
+    layer.zero_grad();
+
+    let outputs: Vec<Matrix<f32>> = layer.forward(inputs.clone());
+    let projections: Vec<Matrix<f32>> = outputs.iter().map(|output| generate_parameter(output.rows, output.cols, -1.0, 1.0)).collect();
+
+    let input_gradients: Vec<Matrix<f32>> = layer.calculate_gradients(projections.clone());
+    let parameter_gradients: Vec<(String, Option<Matrix<f32>>)> = layer.parameters().into_iter().map(|(name, parameter)| (name, parameter.borrow().gradient.clone())).collect();
+
+    // Perturbed passes must not overwrite the cached values, so they run in eval mode.
+    layer.set_training(false);
+
+    let loss = |layer: &mut dyn Layer, inputs: Vec<Matrix<f32>>| -> f64 {
+        let outputs: Vec<Matrix<f32>> = layer.forward(inputs);
+        outputs.iter().zip(projections.iter()).map(|(output, projection)| projected_loss(output, projection)).sum()
+    };
+
+    let mut results: Vec<GradientCheckResult> = vec![];
+
+    let checked_inputs: usize = if check_inputs { inputs.len() } else { 0 };
+
+    for input_index in 0..checked_inputs {
+        let mut numeric: Matrix<f32> = Matrix::new(inputs[input_index].rows, inputs[input_index].cols, 0.0);
+
+        for i in 0..numeric.data.len() {
+            let mut shifted_inputs: Vec<Matrix<f32>> = inputs.clone();
+
+            shifted_inputs[input_index].data[i] = inputs[input_index].data[i] + step;
+            let loss_plus: f64 = loss(layer, shifted_inputs.clone());
+
+            shifted_inputs[input_index].data[i] = inputs[input_index].data[i] - step;
+            let loss_minus: f64 = loss(layer, shifted_inputs);
+
+            numeric.data[i] = ((loss_plus - loss_minus) / (2.0 * step as f64)) as f32;
+        }
+
+        results.push(compare(format!("input_{}", input_index), input_gradients.get(input_index).cloned(), numeric));
+    }
+
+    for (parameter_index, (name, analytic)) in parameter_gradients.into_iter().enumerate() {
+        let (rows, cols) = {
+            let parameters = layer.parameters();
+            let data = parameters[parameter_index].1.borrow();
+            (data.value.rows, data.value.cols)
+        };
+
+        let mut numeric: Matrix<f32> = Matrix::new(rows, cols, 0.0);
+
+        for i in 0..numeric.data.len() {
+            let original: f32 = layer.parameters()[parameter_index].1.borrow().value.data[i];
+
+            layer.parameters_mut()[parameter_index].1.borrow_mut().value.data[i] = original + step;
+            let loss_plus: f64 = loss(layer, inputs.clone());
+
+            layer.parameters_mut()[parameter_index].1.borrow_mut().value.data[i] = original - step;
+            let loss_minus: f64 = loss(layer, inputs.clone());
+
+            layer.parameters_mut()[parameter_index].1.borrow_mut().value.data[i] = original;
+            numeric.data[i] = ((loss_plus - loss_minus) / (2.0 * step as f64)) as f32;
+        }
+
+        results.push(compare(name, analytic, numeric));
+    }
+
+    layer.set_training(was_training);
+
+    GradientCheckReport {
+        layer: layer_name.to_string(),
+        results: results
+    }
+}
+
+
+// Runs the gradient check over every layer in `parts` using small random inputs.
+pub fn check_all_layers() -> Vec<GradientCheckReport> {
+    const SEQUENCE_LENGTH: usize = 4;
+    const D_MODEL: usize = 6;
+    const INNER_SIZE: usize = 8;
+    const VOCABULARY_SIZE: usize = 5;
+
+    let sequence = || generate_parameter(SEQUENCE_LENGTH, D_MODEL, -1.0, 1.0);
+
+    // ReLU variants are not differentiable at zero, so keep the finite differences clear of it.
+    let activation_input = || {
+        let mut input: Matrix<f32> = generate_parameter(SEQUENCE_LENGTH, D_MODEL, -3.0, 3.0);
+
+        for value in input.data.iter_mut() {
+            if value.abs() < 0.1 {
+                *value += 0.2;
+            }
+        }
+
+        input
+    };
+
+    let mut masked_scaled_dot_product: ScaledDotProduct = ScaledDotProduct::new(D_MODEL);
+    masked_scaled_dot_product.causal = true;
+    masked_scaled_dot_product.key_padding = Some(vec![false, false, false, true]);
+
+    // A tile size that does not divide the sequence length, so partial tiles are covered too.
+    let mut tiled_scaled_dot_product: ScaledDotProduct = ScaledDotProduct::new(D_MODEL);
+    tiled_scaled_dot_product.causal = true;
+    tiled_scaled_dot_product.key_padding = Some(vec![false, true, false, false]);
+    tiled_scaled_dot_product.alibi_slope = Some(0.5);
+    tiled_scaled_dot_product.tile_size = Some(3);
+
+    // Two sequences of two rows each, with tiles that straddle the boundary between them.
+    let mut batched_scaled_dot_product: ScaledDotProduct = ScaledDotProduct::new(D_MODEL);
+    batched_scaled_dot_product.causal = true;
+    batched_scaled_dot_product.sequence_length = Some(2);
+    batched_scaled_dot_product.tile_size = Some(3);
+
+    let mut rotary_attention: MultiHeadAttention = MultiHeadAttention::new(D_MODEL, 2, true, -0.5, 0.5);
+    rotary_attention.set_position_encoding(PositionEncoding::Rotary { base: DEFAULT_ROTARY_BASE });
+
+    let mut windowed_attention: MultiHeadAttention = MultiHeadAttention::new(D_MODEL, 2, true, -0.5, 0.5);
+    windowed_attention.set_window(Some(2));
+
+    let mut batched_attention: MultiHeadAttention = MultiHeadAttention::new(D_MODEL, 2, true, -0.5, 0.5);
+    batched_attention.set_position_encoding(PositionEncoding::Rotary { base: DEFAULT_ROTARY_BASE });
+    batched_attention.set_sequence_length(Some(2));
+
+    let mut alibi_attention: MultiHeadAttention = MultiHeadAttention::new(D_MODEL, 3, true, -0.5, 0.5);
+    alibi_attention.set_position_encoding(PositionEncoding::ALiBi);
+
+    let mut multi_query_attention: MultiHeadAttention = MultiHeadAttention::with_kv_heads(D_MODEL, 2, 1, true, -0.5, 0.5);
+    multi_query_attention.set_position_encoding(PositionEncoding::Rotary { base: DEFAULT_ROTARY_BASE });
+
+    // The default initialization of +-0.05 leaves the residual stream with so little variance that the final norm
+    // is far from linear over one finite difference step, so the models are checked at the scale of the other layers.
+    let llm_config = || {
+        let mut config: LLMConfig = LLMConfig::new(VOCABULARY_SIZE, D_MODEL, 2, INNER_SIZE, 2, SEQUENCE_LENGTH);
+
+        config.parameter_min = -0.5;
+        config.parameter_max = 0.5;
+        config
+    };
+
+    let mut grouped_query_config: LLMConfig = llm_config();
+    grouped_query_config.head_count = 6;
+    grouped_query_config.kv_head_count = 3;
+
+    let mut batched_llm: LLM = LLM::from_config(llm_config());
+    batched_llm.set_sequence_length(Some(2));
+
+    // Random gains and biases, so the checks do not rely on the identity initialization.
+    let mut layer_norm: LayerNorm = LayerNorm::new(D_MODEL, DEFAULT_NORM_EPSILON);
+    layer_norm.weights.borrow_mut().value = generate_parameter(1, D_MODEL, 0.5, 1.5);
+    layer_norm.biases.borrow_mut().value = generate_parameter(1, D_MODEL, -0.5, 0.5);
+
+    let mut rms_norm: RMSNorm = RMSNorm::new(D_MODEL, DEFAULT_NORM_EPSILON);
+    rms_norm.weights.borrow_mut().value = generate_parameter(1, D_MODEL, 0.5, 1.5);
+
+    vec![
+        check_gradients("Dense", &mut Dense::new(INNER_SIZE, D_MODEL, -0.5, 0.5), vec![sequence()], DEFAULT_STEP),
+        check_gradients("GELU", &mut GELU::new(), vec![activation_input()], DEFAULT_STEP),
+        check_gradients("ExactGELU", &mut ExactGELU::new(), vec![activation_input()], DEFAULT_STEP),
+        check_gradients("ReLU", &mut ReLU::new(), vec![activation_input()], DEFAULT_STEP),
+        check_gradients("SquaredReLU", &mut SquaredReLU::new(), vec![activation_input()], DEFAULT_STEP),
+        check_gradients("SiLU", &mut SiLU::new(), vec![activation_input()], DEFAULT_STEP),
+        check_gradients("FFN", &mut FFN::new(D_MODEL, INNER_SIZE, -0.5, 0.5), vec![sequence()], DEFAULT_STEP),
+        check_gradients("FFN (squared ReLU)", &mut FFN::with_activation(D_MODEL, INNER_SIZE, Activation::SquaredReLU, -0.5, 0.5), vec![sequence()], DEFAULT_STEP),
+        check_gradients("SwiGLU", &mut SwiGLU::new(D_MODEL, INNER_SIZE, -0.5, 0.5), vec![sequence()], DEFAULT_STEP),
+        check_gradients("LayerNorm", &mut layer_norm, vec![sequence()], DEFAULT_STEP),
+        check_gradients("RMSNorm", &mut rms_norm, vec![sequence()], DEFAULT_STEP),
+        check_gradients("ScaledDotProduct", &mut ScaledDotProduct::new(D_MODEL), vec![sequence(), sequence(), sequence()], DEFAULT_STEP),
+        check_gradients("ScaledDotProduct (masked)", &mut masked_scaled_dot_product, vec![sequence(), sequence(), sequence()], DEFAULT_STEP),
+        check_gradients("ScaledDotProduct (tiled)", &mut tiled_scaled_dot_product, vec![sequence(), sequence(), sequence()], DEFAULT_STEP),
+        check_gradients("ScaledDotProduct (tiled, batched)", &mut batched_scaled_dot_product, vec![sequence(), sequence(), sequence()], DEFAULT_STEP),
+        check_gradients("MultiHeadAttention", &mut MultiHeadAttention::new(D_MODEL, 2, true, -0.5, 0.5), vec![sequence()], DEFAULT_STEP),
+        check_gradients("MultiHeadAttention (rotary)", &mut rotary_attention, vec![sequence()], DEFAULT_STEP),
+        check_gradients("MultiHeadAttention (ALiBi)", &mut alibi_attention, vec![sequence()], DEFAULT_STEP),
+        check_gradients("MultiHeadAttention (sliding window)", &mut windowed_attention, vec![sequence()], DEFAULT_STEP),
+        check_gradients("MultiHeadAttention (grouped-query)", &mut MultiHeadAttention::with_kv_heads(D_MODEL, 6, 2, true, -0.5, 0.5), vec![sequence()], DEFAULT_STEP),
+        check_gradients("MultiHeadAttention (multi-query, rotary)", &mut multi_query_attention, vec![sequence()], DEFAULT_STEP),
+        check_gradients("MultiHeadAttention (batched, rotary)", &mut batched_attention, vec![sequence()], DEFAULT_STEP),
+        check_parameter_gradients("Embedding", &mut Embedding::new(VOCABULARY_SIZE, D_MODEL, -0.5, 0.5), vec![Embedding::indices_to_matrix(&[3, 1, 3, 0])], DEFAULT_STEP),
+        check_gradients("PositionalEmbedding", &mut PositionalEmbedding::new(2 * SEQUENCE_LENGTH, D_MODEL, -0.5, 0.5), vec![sequence()], DEFAULT_STEP),
+        check_gradients("Decoder", &mut Decoder::new(D_MODEL, 2, INNER_SIZE, Normalization::LayerNorm, FeedForward::Standard(Activation::GELU), -0.5, 0.5), vec![sequence()], DEFAULT_STEP),
+        check_gradients("Decoder (RMSNorm, SwiGLU)", &mut Decoder::new(D_MODEL, 2, INNER_SIZE, Normalization::RMSNorm, FeedForward::SwiGLU, -0.5, 0.5), vec![sequence()], DEFAULT_STEP),
+        check_parameter_gradients("LLM", &mut LLM::from_config(llm_config()), vec![Embedding::indices_to_matrix(&[3, 1, 4, 0])], DEFAULT_STEP),
+        check_parameter_gradients("LLM (grouped-query)", &mut LLM::from_config(grouped_query_config), vec![Embedding::indices_to_matrix(&[3, 1, 4, 0])], DEFAULT_STEP),
+        check_parameter_gradients("LLM (batched)", &mut batched_llm, vec![Embedding::indices_to_matrix(&[3, 1, 4, 0])], DEFAULT_STEP)
+    ]
+}
+
+
+// Seeded, so that the test does not depend on rare inputs where f32 finite differences lose precision.
+#[test]
+fn all_layers_pass_gradient_check() {
+    seed_parameters(0);
+
+    for report in check_all_layers() {
+        if !report.passed(DEFAULT_TOLERANCE) {
+            report.display();
+        }
+
+        assert!(report.passed(DEFAULT_TOLERANCE), "{} failed the gradient check.", report.layer);
+    }
+}
@@ -1 +1,4 @@
-pub mod parts;
\ No newline at end of file
+pub mod parts;
+#[cfg(test)]
+pub mod gradient_check;
+pub mod checkpoint;
\ No newline at end of file
@@ -1,16 +1,32 @@
+use std::cell::{Ref, RefCell, RefMut};
 use std::f32::{consts::PI};
+use std::ops::Range;
+use std::rc::Rc;
 
-use rand::Rng;
+use rand::{Rng, RngCore, SeedableRng};
+use rand::rngs::StdRng;
 
 use crate::matrix::matrix::Matrix;
 
-fn generate_parameter(rows: usize, cols: usize, min: f32, max: f32) -> Matrix<f32> {
-    let mut rng = rand::thread_rng();
+thread_local! {
+    static PARAMETER_RNG: RefCell<StdRng> = RefCell::new(StdRng::from_entropy());
+}
+
+// Reseeds the generator behind `generate_parameter` on the current thread, so that initialization is reproducible.
+pub fn seed_parameters(seed: u64) {
+    PARAMETER_RNG.with(|rng| *rng.borrow_mut() = StdRng::seed_from_u64(seed));
+}
+
+pub fn generate_parameter(rows: usize, cols: usize, min: f32, max: f32) -> Matrix<f32> {
     let mut data: Vec<f32> = vec![];
 
-    for _ in 0..(rows * cols) {
-        data.push(rng.gen_range(min..max));
-    }
+    PARAMETER_RNG.with(|rng| {
+        let mut rng = rng.borrow_mut();
+
+        for _ in 0..(rows * cols) {
+            data.push(rng.gen_range(min..max));
+        }
+    });
 
     Matrix {
         rows: rows,
@@ -20,319 +36,3165 @@ fn generate_parameter(rows: usize, cols: usize, min: f32, max: f32) -> Matrix<f3
 }
 
 
+pub struct ParameterData {
+    pub value: Matrix<f32>,
+    pub gradient: Option<Matrix<f32>>
+}
+
+// A trainable value together with the gradient accumulated for it since the last `zero_grad`.
+// `share` returns another handle to the same storage, so tied weights (such as the token embedding and the
+// output projection) read one value and accumulate the gradients of every use into one place.
+pub struct Parameter {
+    data: Rc<RefCell<ParameterData>>
+}
+
+impl Parameter {
+    pub fn new(value: Matrix<f32>) -> Parameter {
+        Parameter {
+            data: Rc::new(RefCell::new(ParameterData {
+                value: value,
+                gradient: None
+            }))
+        }
+    }
+
+    pub fn share(&self) -> Parameter {
+        Parameter {
+            data: Rc::clone(&self.data)
+        }
+    }
+
+    pub fn is_shared_with(&self, other: &Parameter) -> bool {
+        Rc::ptr_eq(&self.data, &other.data)
+    }
+
+    pub fn borrow(&self) -> Ref<'_, ParameterData> {
+        self.data.borrow()
+    }
+
+    pub fn borrow_mut(&self) -> RefMut<'_, ParameterData> {
+        self.data.borrow_mut()
+    }
+
+    pub fn value(&self) -> Matrix<f32> {
+        self.data.borrow().value.clone()
+    }
+
+    pub fn accumulate_gradient(&self, gradient: Matrix<f32>) {
+        let mut data: RefMut<'_, ParameterData> = self.data.borrow_mut();
+
+        data.gradient = match data.gradient.take() {
+            Some(current) => Some(current + gradient),
+            None => Some(gradient)
+        };
+    }
+}
+
+// Prepends a layer's field name to the names of its children's parameters, e.g. "inner_dense.weights".
+pub fn prefix_parameters<T>(prefix: &str, parameters: Vec<(String, T)>) -> Vec<(String, T)> {
+    parameters.into_iter().map(|(name, parameter)| (format!("{}.{}", prefix, name), parameter)).collect()
+}
+
+
 pub trait Layer {
+    // Runs the layer. While training, the inputs needed by `calculate_gradients` are cached.
+    fn forward(&mut self, inputs: Vec<Matrix<f32>>) -> Vec<Matrix<f32>>;
+
+    // Returns the gradients with respect to each input, adding parameter gradients onto any already stored.
     fn calculate_gradients(&mut self, previous_gradients: Vec<Matrix<f32>>) -> Vec<Matrix<f32>>;
-    fn adjust_parameters(&mut self, learning_rate: f32);
+
+    fn parameters(&self) -> Vec<(String, &Parameter)> {
+        vec![]
+    }
+
+    fn parameters_mut(&mut self) -> Vec<(String, &mut Parameter)> {
+        vec![]
+    }
+
+    fn set_training(&mut self, training: bool);
+    fn is_training(&self) -> bool;
+
+    fn train(&mut self) {
+        self.set_training(true);
+    }
+
+    fn eval(&mut self) {
+        self.set_training(false);
+    }
+
+    fn zero_grad(&mut self) {
+        for (_, parameter) in self.parameters_mut() {
+            parameter.borrow_mut().gradient = None;
+        }
+    }
+
+    fn parameter_count(&self) -> usize {
+        self.parameters().iter().map(|(_, parameter)| parameter.borrow().value.data.len()).sum()
+    }
 }
 
 
 pub struct Dense {
-    pub weights: Matrix<f32>,
-    pub weights_gradients: Option<Matrix<f32>>,
-
-    pub biases: Matrix<f32>,
-    pub biases_gradients: Option<Matrix<f32>>,
+    pub weights: Parameter,
+    pub biases: Parameter,
 
-    pub previous_input: Option<Matrix<f32>>
+    pub previous_input: Option<Matrix<f32>>,
+    pub training: bool
 }
 
 impl Dense {
     pub fn new(nodes: usize, input_size: usize, parameter_min: f32, parameter_max: f32) -> Dense {
         Dense {
-            weights: generate_parameter(input_size, nodes, parameter_min, parameter_max),
-            weights_gradients: None,
+            weights: Parameter::new(generate_parameter(input_size, nodes, parameter_min, parameter_max)),
+            biases: Parameter::new(generate_parameter(1, nodes, parameter_min, parameter_max)),
 
-            biases: generate_parameter(1, nodes, parameter_min, parameter_max),
-            biases_gradients: None,
-
-            previous_input: None
-        }    
+            previous_input: None,
+            training: true
+        }
     }
+}
+
+impl Layer for Dense {
+    fn forward(&mut self, inputs: Vec<Matrix<f32>>) -> Vec<Matrix<f32>> {
+        let c_input = inputs[0].clone();
 
-    fn compute(&mut self, input: Matrix<f32>, handle_gradients: bool) -> Matrix<f32> {
-        let c_input = input;
-        
-        if handle_gradients {
+        if self.training {
             self.previous_input = Some(c_input.clone());
         }
 
-        c_input * self.weights.clone() + self.biases.clone()
+        vec![(c_input * self.weights.value()).add_row_vector(self.biases.value())]
     }
-}
 
-impl Layer for Dense {
     fn calculate_gradients(&mut self, previous_gradients: Vec<Matrix<f32>>) -> Vec<Matrix<f32>> {
         if self.previous_input.is_none() {
             println!("Dense Error - Previous input is none.");
             return vec![];
         }
 
-        self.weights_gradients = Some(self.previous_input.clone().unwrap().transpose() * previous_gradients[0].clone());
-        self.biases_gradients = Some(previous_gradients[0].clone());
+        self.weights.accumulate_gradient(self.previous_input.clone().unwrap().transpose() * previous_gradients[0].clone());
+        self.biases.accumulate_gradient(previous_gradients[0].col_sums());
+
 
+        return vec![previous_gradients[0].clone() * self.weights.value().transpose()];
+    }
+
+    fn parameters(&self) -> Vec<(String, &Parameter)> {
+        vec![("weights".to_string(), &self.weights), ("biases".to_string(), &self.biases)]
+    }
 
-        return vec![previous_gradients[0].clone() * self.weights.clone().transpose()];
+    fn parameters_mut(&mut self) -> Vec<(String, &mut Parameter)> {
+        vec![("weights".to_string(), &mut self.weights), ("biases".to_string(), &mut self.biases)]
     }
 
+    fn set_training(&mut self, training: bool) {
+        self.training = training;
+    }
 
-    fn adjust_parameters(&mut self, learning_rate: f32) {
-        self.weights = self.weights.clone() - self.weights_gradients.clone().unwrap() * learning_rate;
-        self.biases = self.biases.clone() - self.biases_gradients.clone().unwrap() * learning_rate;
+    fn is_training(&self) -> bool {
+        self.training
     }
 }
 
 
+const GELU_ALPHA: f32 = 0.044715;
+
 pub struct GELU {
-    pub previous_input: Option<Matrix<f32>>
+    pub previous_input: Option<Matrix<f32>>,
+    pub training: bool
 }
 
 impl GELU {
     pub fn new() -> GELU {
         GELU {
-            previous_input: None
+            previous_input: None,
+            training: true
         }
     }
+}
+
+impl Layer for GELU {
+    fn forward(&mut self, inputs: Vec<Matrix<f32>>) -> Vec<Matrix<f32>> {
+        let input: Matrix<f32> = inputs[0].clone();
 
-    fn compute(&mut self, input: Matrix<f32>, handle_gradients: bool) -> Matrix<f32> {
-        if handle_gradients {
+        if self.training {
             self.previous_input = Some(input.clone());
         }
 
-        input.clone() * (((input.clone() +  input.clone().pow_unit(3.0) * 0.044715) * (2.0 / PI).sqrt()).tanh() + 1.0) * 0.5
+        vec![input.element_mult(((input.clone() +  input.clone().pow_unit(3.0) * GELU_ALPHA) * (2.0 / PI).sqrt()).tanh() + 1.0) * 0.5]
     }
-}
 
-impl Layer for GELU {
     fn calculate_gradients(&mut self, previous_gradients: Vec<Matrix<f32>>) -> Vec<Matrix<f32>> {
         if self.previous_input.is_none() {
             println!("GELU Error - Previous input is none.");
             return vec![];
         }
 
-        const ALPHA_CONSTANT: f32 = 0.044714;
-        let one_matrix: Matrix<f32> = Matrix::new(self.previous_input.as_ref().unwrap().rows, self.previous_input.as_ref().unwrap().cols, 1.0);
-        
-        let f: Matrix<f32> = self.previous_input.clone().unwrap() * 0.5;
-        let g: Matrix<f32> = (self.previous_input.clone().unwrap() +  self.previous_input.clone().unwrap().pow_unit(3.0) * ALPHA_CONSTANT * (2.0 / PI).sqrt()).tanh() + 1.0;
+        let input: Matrix<f32> = self.previous_input.clone().unwrap();
+        let one_matrix: Matrix<f32> = Matrix::new(input.rows, input.cols, 1.0);
+
+        // GELU(x) = f(x) * g(x), where f(x) = x / 2 and g(x) = 1 + tanh(sqrt(2 / PI) * (x + ALPHA * x^3)).
+        let tanh_result: Matrix<f32> = ((input.clone() + input.clone().pow_unit(3.0) * GELU_ALPHA) * (2.0 / PI).sqrt()).tanh();
+
+        let f: Matrix<f32> = input.clone() * 0.5;
+        let g: Matrix<f32> = tanh_result.clone() + 1.0;
 
         let f_diff: Matrix<f32> = one_matrix.clone() * 0.5;
-        let g_diff: Matrix<f32> = (one_matrix.clone()) / ((self.previous_input.clone().unwrap() * (2.0 / PI) + self.previous_input.clone().unwrap().pow_unit(3.0) * (2.0 * ALPHA_CONSTANT / PI)).cosh().pow_unit(2.0)) * (self.previous_input.clone().unwrap().pow_unit(3.0) * (6.0 * ALPHA_CONSTANT / PI) + (2.0 / PI));
+        let g_diff: Matrix<f32> = (one_matrix.clone() - tanh_result.element_mult(tanh_result.clone())).element_mult((input.clone().pow_unit(2.0) * (3.0 * GELU_ALPHA) + 1.0) * (2.0 / PI).sqrt());
 
-        vec![(f_diff * g + f * g_diff) * previous_gradients[0].clone()]
+        vec![(f_diff.element_mult(g) + f.element_mult(g_diff)).element_mult(previous_gradients[0].clone())]
     }
 
-    fn adjust_parameters(&mut self, _learning_rate: f32) {}
+    fn set_training(&mut self, training: bool) {
+        self.training = training;
+    }
+
+    fn is_training(&self) -> bool {
+        self.training
+    }
 }
 
 
-pub struct FFN {
-    pub inner_dense: Dense,
-    pub outer_dense: Dense,
-    pub activation_layer: GELU
+fn relu(x: f32) -> f32 {
+    x.max(0.0)
 }
 
-impl FFN {
-    pub fn new(input_size: usize, inner_size: usize, parameter_min: f32, parameter_max: f32) -> FFN {
-        FFN {
-            inner_dense: Dense::new(inner_size, input_size, parameter_min, parameter_max),
-            outer_dense: Dense::new(input_size, inner_size, parameter_min, parameter_max),
-            activation_layer: GELU::new()
+fn relu_derivative(x: f32) -> f32 {
+    if x > 0.0 { 1.0 } else { 0.0 }
+}
+
+pub struct ReLU {
+    pub previous_input: Option<Matrix<f32>>,
+    pub training: bool
+}
+
+impl ReLU {
+    pub fn new() -> ReLU {
+        ReLU {
+            previous_input: None,
+            training: true
+        }
+    }
+}
+
+impl Layer for ReLU {
+    fn forward(&mut self, inputs: Vec<Matrix<f32>>) -> Vec<Matrix<f32>> {
+        let input: Matrix<f32> = inputs[0].clone();
+
+        if self.training {
+            self.previous_input = Some(input.clone());
+        }
+
+        vec![input.apply(relu)]
+    }
+
+    fn calculate_gradients(&mut self, previous_gradients: Vec<Matrix<f32>>) -> Vec<Matrix<f32>> {
+        if self.previous_input.is_none() {
+            println!("ReLU Error - Previous input is none.");
+            return vec![];
         }
+
+        let input: Matrix<f32> = self.previous_input.clone().unwrap();
+
+        vec![input.apply(relu_derivative).element_mult(previous_gradients[0].clone())]
     }
 
+    fn set_training(&mut self, training: bool) {
+        self.training = training;
+    }
 
-    pub fn compute(&mut self, input: Matrix<f32>, handle_gradients: bool) -> Matrix<f32> {
-        let result_1: Matrix<f32> = self.inner_dense.compute(input, handle_gradients);
-        let result_2: Matrix<f32> = self.activation_layer.compute(result_1, handle_gradients);
-        self.outer_dense.compute(result_2, handle_gradients)
+    fn is_training(&self) -> bool {
+        self.training
     }
 }
 
-impl Layer for FFN {
+
+// relu(x)^2, as used by the BitNet b1.58 FFNs.
+pub struct SquaredReLU {
+    pub previous_input: Option<Matrix<f32>>,
+    pub training: bool
+}
+
+impl SquaredReLU {
+    pub fn new() -> SquaredReLU {
+        SquaredReLU {
+            previous_input: None,
+            training: true
+        }
+    }
+}
+
+impl Layer for SquaredReLU {
+    fn forward(&mut self, inputs: Vec<Matrix<f32>>) -> Vec<Matrix<f32>> {
+        let input: Matrix<f32> = inputs[0].clone();
+
+        if self.training {
+            self.previous_input = Some(input.clone());
+        }
+
+        vec![input.apply(relu).pow_unit(2.0)]
+    }
+
     fn calculate_gradients(&mut self, previous_gradients: Vec<Matrix<f32>>) -> Vec<Matrix<f32>> {
-        let result_1: Vec<Matrix<f32>> = self.outer_dense.calculate_gradients(previous_gradients);
-        let result_2: Vec<Matrix<f32>> = self.activation_layer.calculate_gradients(result_1);
-        self.inner_dense.calculate_gradients(result_2)
+        if self.previous_input.is_none() {
+            println!("SquaredReLU Error - Previous input is none.");
+            return vec![];
+        }
+
+        let input: Matrix<f32> = self.previous_input.clone().unwrap();
+
+        // d/dx relu(x)^2 = 2 * relu(x)
+        vec![(input.apply(relu) * 2.0).element_mult(previous_gradients[0].clone())]
     }
 
+    fn set_training(&mut self, training: bool) {
+        self.training = training;
+    }
 
-    fn adjust_parameters(&mut self, learning_rate: f32) {
-        self.inner_dense.adjust_parameters(learning_rate);
-        self.outer_dense.adjust_parameters(learning_rate);
+    fn is_training(&self) -> bool {
+        self.training
     }
 }
 
 
-pub struct LayerNorm {
-    pub weights: Matrix<f32>,
-    pub weights_gradients: Option<Matrix<f32>>,
+fn sigmoid(x: f32) -> f32 {
+    1.0 / (1.0 + (-x).exp())
+}
 
-    pub biases: Matrix<f32>,
-    pub biases_gradients: Option<Matrix<f32>>,
+fn silu(x: f32) -> f32 {
+    x * sigmoid(x)
+}
 
-    pub previous_variances: Option<Matrix<f32>>,
-    pub previous_finals: Option<Matrix<f32>>
+// d/dx x * sigmoid(x) = sigmoid(x) * (1 + x * (1 - sigmoid(x)))
+fn silu_derivative(x: f32) -> f32 {
+    let s: f32 = sigmoid(x);
+    s * (1.0 + x * (1.0 - s))
 }
 
-impl LayerNorm {
-    pub fn new(input_size: usize, dimensions: usize, parameter_min: f32, parameter_max: f32) -> LayerNorm {
-        LayerNorm {
-            weights: generate_parameter(input_size, dimensions, parameter_min, parameter_max),
-            weights_gradients: None,
-            
-            biases: generate_parameter(input_size, dimensions, parameter_min, parameter_max),
-            biases_gradients: None,
-        
-            previous_variances: None,
-            previous_finals: None
+pub struct SiLU {
+    pub previous_input: Option<Matrix<f32>>,
+    pub training: bool
+}
+
+impl SiLU {
+    pub fn new() -> SiLU {
+        SiLU {
+            previous_input: None,
+            training: true
         }
     }
+}
 
+impl Layer for SiLU {
+    fn forward(&mut self, inputs: Vec<Matrix<f32>>) -> Vec<Matrix<f32>> {
+        let input: Matrix<f32> = inputs[0].clone();
 
-    pub fn compute(&mut self, input: Matrix<f32>, handle_gradients: bool) -> Matrix<f32> {
-        const EPSILLON: f32 = 0.0005;
+        if self.training {
+            self.previous_input = Some(input.clone());
+        }
 
-        let mut means: Matrix<f32> = Matrix::new(input.rows, 1, 0.0);
+        vec![input.apply(silu)]
+    }
 
-        for row in 0..input.rows {
-            for col in 0..input.cols {
-                means.set(row, 0, means.get(row, 0) + input.get(row, col));
-            }
+    fn calculate_gradients(&mut self, previous_gradients: Vec<Matrix<f32>>) -> Vec<Matrix<f32>> {
+        if self.previous_input.is_none() {
+            println!("SiLU Error - Previous input is none.");
+            return vec![];
         }
 
-        means = means.clone() / input.cols as f32;
-    
-        let mut variances: Matrix<f32> = Matrix::new(input.rows, 1, 0.0);
+        let input: Matrix<f32> = self.previous_input.clone().unwrap();
 
-        for row in 0..input.rows {
-            for col in 0..input.cols {
-                variances.set(row, 0, variances.get(row, 0) + (input.get(row, col) - means.get(row, 0)).powf(2.0));
-            }
-        }
+        vec![input.apply(silu_derivative).element_mult(previous_gradients[0].clone())]
+    }
 
-        variances = variances.clone() / input.cols as f32;
-        
-        let mut normalized_values: Matrix<f32> = Matrix::new(input.rows, 1, 0.0);
+    fn set_training(&mut self, training: bool) {
+        self.training = training;
+    }
 
-        for row in 0..input.rows {
-            for col in 0..input.cols {
-                normalized_values.set(row, col, (input.get(row, col) - means.get(row, 0)) / (variances.get(row, 0) + EPSILLON).sqrt());
-            }
+    fn is_training(&self) -> bool {
+        self.training
+    }
+}
+
+
+// Abramowitz and Stegun 7.1.26, accurate to about 1.5e-7, which is below f32 precision for GELU.
+fn erf(x: f32) -> f32 {
+    let t: f32 = 1.0 / (1.0 + 0.3275911 * x.abs());
+    let polynomial: f32 = t * (0.2548296 + t * (-0.28449674 + t * (1.4214138 + t * (-1.4531521 + t * 1.0614054))));
+    let result: f32 = 1.0 - polynomial * (-x * x).exp();
+
+    if x < 0.0 { -result } else { result }
+}
+
+fn exact_gelu(x: f32) -> f32 {
+    0.5 * x * (1.0 + erf(x / 2.0_f32.sqrt()))
+}
+
+// d/dx x * cdf(x) = cdf(x) + x * pdf(x)
+fn exact_gelu_derivative(x: f32) -> f32 {
+    0.5 * (1.0 + erf(x / 2.0_f32.sqrt())) + x * (-0.5 * x * x).exp() / (2.0 * PI).sqrt()
+}
+
+// GELU using the exact normal CDF rather than the tanh approximation. This is what
+// `tf.keras.activations.gelu` computes by default in the Python model.
+pub struct ExactGELU {
+    pub previous_input: Option<Matrix<f32>>,
+    pub training: bool
+}
+
+impl ExactGELU {
+    pub fn new() -> ExactGELU {
+        ExactGELU {
+            previous_input: None,
+            training: true
         }
+    }
+}
+
+impl Layer for ExactGELU {
+    fn forward(&mut self, inputs: Vec<Matrix<f32>>) -> Vec<Matrix<f32>> {
+        let input: Matrix<f32> = inputs[0].clone();
 
-        if handle_gradients {
-            self.previous_variances = Some(variances.clone());
-            self.previous_finals = Some(normalized_values.clone());
+        if self.training {
+            self.previous_input = Some(input.clone());
         }
 
-        self.weights.clone().element_mult(normalized_values) + self.biases.clone()
+        vec![input.apply(exact_gelu)]
     }
-}
 
-impl Layer for LayerNorm {
     fn calculate_gradients(&mut self, previous_gradients: Vec<Matrix<f32>>) -> Vec<Matrix<f32>> {
-        const EPSILLON: f32 = 0.0005;
-        
-        if self.previous_variances.is_none() {
-            println!("LayerNorm Error - Previous variances are none.");
-            return vec![];
-        } else if self.previous_finals.is_none() {
-            println!("LayerNorm Error - Previous finals are none.");
+        if self.previous_input.is_none() {
+            println!("ExactGELU Error - Previous input is none.");
             return vec![];
         }
 
-        self.weights_gradients = Some(self.previous_finals.clone().unwrap().element_mult(previous_gradients[0].clone()));
-        self.biases_gradients = Some(previous_gradients[0].clone());
+        let input: Matrix<f32> = self.previous_input.clone().unwrap();
 
-        let final_gradients: Matrix<f32> = previous_gradients[0].clone().element_mult(self.weights.clone());
+        vec![input.apply(exact_gelu_derivative).element_mult(previous_gradients[0].clone())]
+    }
 
-        let mut input_gradients: Matrix<f32> = Matrix::new(self.previous_finals.clone().unwrap().rows, self.previous_finals.clone().unwrap().cols, 0.0);
+    fn set_training(&mut self, training: bool) {
+        self.training = training;
+    }
 
-        for row in 0..input_gradients.rows {
-            let mut final_summation: f32 = 0.0;
-            let mut multiplied_final_summation: f32 = 0.0;
+    fn is_training(&self) -> bool {
+        self.training
+    }
+}
 
-            for col in 0..input_gradients.cols {
-                final_summation += final_gradients.get(row, col);
-                multiplied_final_summation += final_gradients.get(row, col) * self.previous_finals.clone().unwrap().get(row, col);
-            }
 
-            for col in 0..input_gradients.cols {
-                input_gradients.set(row, col, (final_gradients.get(row, col) - (1.0 / input_gradients.cols as f32) * final_summation - self.previous_finals.clone().unwrap().get(row, col) * multiplied_final_summation) * 1.0 / (self.previous_variances.clone().unwrap().get(row, 0) + EPSILLON).sqrt());
-            }
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Activation {
+    GELU,
+    ExactGELU,
+    ReLU,
+    SquaredReLU,
+    SiLU
+}
+
+pub fn activation_layer(activation: Activation) -> Box<dyn Layer> {
+    match activation {
+        Activation::GELU => Box::new(GELU::new()),
+        Activation::ExactGELU => Box::new(ExactGELU::new()),
+        Activation::ReLU => Box::new(ReLU::new()),
+        Activation::SquaredReLU => Box::new(SquaredReLU::new()),
+        Activation::SiLU => Box::new(SiLU::new())
+    }
+}
+
+
+pub struct FFN {
+    pub inner_dense: Dense,
+    pub outer_dense: Dense,
+    pub activation_layer: Box<dyn Layer>
+}
+
+impl FFN {
+    pub fn new(input_size: usize, inner_size: usize, parameter_min: f32, parameter_max: f32) -> FFN {
+        FFN::with_activation(input_size, inner_size, Activation::GELU, parameter_min, parameter_max)
+    }
+
+    pub fn with_activation(input_size: usize, inner_size: usize, activation: Activation, parameter_min: f32, parameter_max: f32) -> FFN {
+        FFN {
+            inner_dense: Dense::new(inner_size, input_size, parameter_min, parameter_max),
+            outer_dense: Dense::new(input_size, inner_size, parameter_min, parameter_max),
+            activation_layer: activation_layer(activation)
         }
+    }
+}
 
-        vec![input_gradients]
+impl Layer for FFN {
+    fn forward(&mut self, inputs: Vec<Matrix<f32>>) -> Vec<Matrix<f32>> {
+        let result_1: Vec<Matrix<f32>> = self.inner_dense.forward(inputs);
+        let result_2: Vec<Matrix<f32>> = self.activation_layer.forward(result_1);
+        self.outer_dense.forward(result_2)
+    }
+
+    fn calculate_gradients(&mut self, previous_gradients: Vec<Matrix<f32>>) -> Vec<Matrix<f32>> {
+        let result_1: Vec<Matrix<f32>> = self.outer_dense.calculate_gradients(previous_gradients);
+        let result_2: Vec<Matrix<f32>> = self.activation_layer.calculate_gradients(result_1);
+        self.inner_dense.calculate_gradients(result_2)
+    }
+
+    fn parameters(&self) -> Vec<(String, &Parameter)> {
+        let mut parameters: Vec<(String, &Parameter)> = prefix_parameters("inner_dense", self.inner_dense.parameters());
+        parameters.extend(prefix_parameters("outer_dense", self.outer_dense.parameters()));
+        parameters
+    }
+
+    fn parameters_mut(&mut self) -> Vec<(String, &mut Parameter)> {
+        let mut parameters: Vec<(String, &mut Parameter)> = prefix_parameters("inner_dense", self.inner_dense.parameters_mut());
+        parameters.extend(prefix_parameters("outer_dense", self.outer_dense.parameters_mut()));
+        parameters
     }
 
+    fn set_training(&mut self, training: bool) {
+        self.inner_dense.set_training(training);
+        self.outer_dense.set_training(training);
+        self.activation_layer.set_training(training);
+    }
 
-    fn adjust_parameters(&mut self, learning_rate: f32) {
-        self.weights = self.weights.clone() - self.weights_gradients.clone().unwrap() * learning_rate;
-        self.biases = self.biases.clone() - self.biases_gradients.clone().unwrap() * learning_rate;
+    fn is_training(&self) -> bool {
+        self.inner_dense.is_training()
     }
 }
 
 
-struct ScaledDotProduct {
-    d_model: usize,
-    previous_inputs: Option<Vec<Matrix<f32>>>
+// Gated FFN from "GLU Variants Improve Transformer": down(silu(gate(x)) * up(x)).
+pub struct SwiGLU {
+    pub gate_dense: Dense,
+    pub up_dense: Dense,
+    pub down_dense: Dense,
+    pub activation_layer: SiLU,
+
+    pub previous_activations: Option<Matrix<f32>>,
+    pub previous_ups: Option<Matrix<f32>>,
+    pub training: bool
 }
 
-impl ScaledDotProduct {
-    pub fn new(d_model: usize) -> ScaledDotProduct {
-        ScaledDotProduct {
-            d_model: d_model,
-            previous_inputs: None
+impl SwiGLU {
+    pub fn new(input_size: usize, inner_size: usize, parameter_min: f32, parameter_max: f32) -> SwiGLU {
+        SwiGLU {
+            gate_dense: Dense::new(inner_size, input_size, parameter_min, parameter_max),
+            up_dense: Dense::new(inner_size, input_size, parameter_min, parameter_max),
+            down_dense: Dense::new(input_size, inner_size, parameter_min, parameter_max),
+            activation_layer: SiLU::new(),
+
+            previous_activations: None,
+            previous_ups: None,
+            training: true
         }
     }
+}
+
+impl Layer for SwiGLU {
+    fn forward(&mut self, inputs: Vec<Matrix<f32>>) -> Vec<Matrix<f32>> {
+        let gates: Vec<Matrix<f32>> = self.gate_dense.forward(inputs.clone());
+        let activations: Matrix<f32> = self.activation_layer.forward(gates)[0].clone();
+        let ups: Matrix<f32> = self.up_dense.forward(inputs)[0].clone();
 
-    pub fn compute(&mut self, q: Matrix<f32>, k: Matrix<f32>, v: Matrix<f32>, handle_gradients: bool) -> Matrix<f32> {
-        if handle_gradients {
-            self.previous_inputs = Some(vec![q.clone(), k.clone(), v.clone()]);
+        let gated: Matrix<f32> = activations.element_mult(ups.clone());
+
+        if self.training {
+            self.previous_activations = Some(activations);
+            self.previous_ups = Some(ups);
         }
 
-        (q.clone() * k.clone().transpose() / (self.d_model as f32).sqrt()).row_softmax() * v.clone()
+        self.down_dense.forward(vec![gated])
     }
-}
 
-impl Layer for ScaledDotProduct {
     fn calculate_gradients(&mut self, previous_gradients: Vec<Matrix<f32>>) -> Vec<Matrix<f32>> {
-        if self.previous_inputs.is_none() {
-            println!("Scaled Dot Product Error - Previous inputs are none.");
+        if self.previous_activations.is_none() {
+            println!("SwiGLU Error - Previous activations are none.");
+            return vec![];
+        } else if self.previous_ups.is_none() {
+            println!("SwiGLU Error - Previous ups are none.");
             return vec![];
         }
 
-        let q = self.previous_inputs.as_ref().unwrap()[0].clone();
-        let k = self.previous_inputs.as_ref().unwrap()[1].clone();
-        let v = self.previous_inputs.as_ref().unwrap()[2].clone();
+        let activations: Matrix<f32> = self.previous_activations.clone().unwrap();
+        let ups: Matrix<f32> = self.previous_ups.clone().unwrap();
 
-        let pre_scaled_values = q.clone() * k.clone().transpose() / (self.d_model as f32).sqrt();
-        let mut pre_scaled_gradients: Matrix<f32> = Matrix::new(pre_scaled_values.rows, pre_scaled_values.cols, 0.0);
+        let gated_gradients: Matrix<f32> = self.down_dense.calculate_gradients(previous_gradients)[0].clone();
 
-        let scaled_value = pre_scaled_values.clone().row_softmax();
-        let scaled_value_gradient = previous_gradients[0].clone() * v.clone().transpose();
-        
-        let v_gradient = scaled_value.transpose() * previous_gradients[0].clone();
-        
-        for row in 0..pre_scaled_values.rows {
-            let mut total: f32 = 0.0;
-            let mut above_total: f32 = 0.0;
+        let activation_gradients: Vec<Matrix<f32>> = self.activation_layer.calculate_gradients(vec![gated_gradients.element_mult(ups)]);
+        let gate_gradients: Matrix<f32> = self.gate_dense.calculate_gradients(activation_gradients)[0].clone();
+        let up_gradients: Matrix<f32> = self.up_dense.calculate_gradients(vec![gated_gradients.element_mult(activations)])[0].clone();
 
-            for col in 0..pre_scaled_gradients.cols {
-                total += pre_scaled_values.get(row, col).exp();
-                above_total += pre_scaled_values.get(row, col)
-            }
+        // The input feeds both the gate and up projections, so their gradients are summed.
+        vec![gate_gradients + up_gradients]
+    }
 
-            for col in 0..pre_scaled_gradients.cols {
-                pre_scaled_gradients.set(row, col, (scaled_value_gradient.get(row, col) * above_total * pre_scaled_values.get(row, col)) / total)
-            }
-        }
+    fn parameters(&self) -> Vec<(String, &Parameter)> {
+        let mut parameters: Vec<(String, &Parameter)> = prefix_parameters("gate_dense", self.gate_dense.parameters());
+        parameters.extend(prefix_parameters("up_dense", self.up_dense.parameters()));
+        parameters.extend(prefix_parameters("down_dense", self.down_dense.parameters()));
+        parameters
+    }
 
-        let q_gradient = pre_scaled_gradients.clone() * k / (self.d_model as f32).sqrt();
-        let k_gradient = pre_scaled_gradients.clone().transpose() * q / (self.d_model as f32).sqrt();
+    fn parameters_mut(&mut self) -> Vec<(String, &mut Parameter)> {
+        let mut parameters: Vec<(String, &mut Parameter)> = prefix_parameters("gate_dense", self.gate_dense.parameters_mut());
+        parameters.extend(prefix_parameters("up_dense", self.up_dense.parameters_mut()));
+        parameters.extend(prefix_parameters("down_dense", self.down_dense.parameters_mut()));
+        parameters
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.training = training;
+
+        self.gate_dense.set_training(training);
+        self.up_dense.set_training(training);
+        self.down_dense.set_training(training);
+        self.activation_layer.set_training(training);
+    }
 
-        vec![q_gradient, k_gradient, v_gradient]
+    fn is_training(&self) -> bool {
+        self.training
     }
+}
+
+
+// The feed-forward sublayer used in each decoder block.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FeedForward {
+    Standard(Activation),
+    SwiGLU
+}
 
-    fn adjust_parameters(&mut self, _learning_rate: f32) {}
-}
\ No newline at end of file
+pub fn feed_forward_layer(feed_forward: FeedForward, d_model: usize, inner_size: usize, parameter_min: f32, parameter_max: f32) -> Box<dyn Layer> {
+    match feed_forward {
+        FeedForward::Standard(activation) => Box::new(FFN::with_activation(d_model, inner_size, activation, parameter_min, parameter_max)),
+        FeedForward::SwiGLU => Box::new(SwiGLU::new(d_model, inner_size, parameter_min, parameter_max))
+    }
+}
+
+
+// Inverted dropout: in training each value is zeroed with probability `rate` and the survivors are scaled by
+// 1 / (1 - rate), so eval mode can pass inputs through unchanged. The mask is kept for the backward pass.
+pub struct Dropout {
+    pub rate: f32,
+    rng: StdRng,
+
+    pub previous_mask: Option<Matrix<f32>>,
+    pub training: bool
+}
+
+impl Dropout {
+    pub fn new(rate: f32, seed: u64) -> Dropout {
+        // A rate of 1 would scale the survivors by infinity, so invalid rates are rejected outright.
+        assert!((0.0..1.0).contains(&rate), "Dropout Error - Rate ({}) must be in [0, 1).", rate);
+
+        Dropout {
+            rate: rate,
+            rng: StdRng::seed_from_u64(seed),
+
+            previous_mask: None,
+            training: true
+        }
+    }
+
+    // Zero with probability `rate`, and 1 / (1 - rate) otherwise.
+    fn draw_mask(rate: f32, rows: usize, cols: usize, rng: &mut StdRng) -> Matrix<f32> {
+        let scale: f32 = 1.0 / (1.0 - rate);
+        let mut mask: Matrix<f32> = Matrix::new(rows, cols, 0.0);
+
+        for value in mask.data.iter_mut() {
+            if rng.r#gen::<f32>() >= rate {
+                *value = scale;
+            }
+        }
+
+        mask
+    }
+
+    // A seed for `mask_from_seed`, drawn from the layer's generator so that every call gets new masks.
+    pub fn next_seed(&mut self) -> u64 {
+        self.rng.next_u64()
+    }
+
+    // A (rows x cols) mask that depends only on `seed`, for layers that rebuild the same mask piece by piece in
+    // their backward pass instead of storing it.
+    pub fn mask_from_seed(&self, rows: usize, cols: usize, seed: u64) -> Matrix<f32> {
+        Dropout::draw_mask(self.rate, rows, cols, &mut StdRng::seed_from_u64(seed))
+    }
+}
+
+impl Layer for Dropout {
+    fn forward(&mut self, inputs: Vec<Matrix<f32>>) -> Vec<Matrix<f32>> {
+        let input: Matrix<f32> = inputs[0].clone();
+
+        if !self.training {
+            return vec![input];
+        }
+
+        let mask: Matrix<f32> = Dropout::draw_mask(self.rate, input.rows, input.cols, &mut self.rng);
+        let result: Matrix<f32> = input.element_mult(mask.clone());
+        self.previous_mask = Some(mask);
+
+        vec![result]
+    }
+
+    fn calculate_gradients(&mut self, previous_gradients: Vec<Matrix<f32>>) -> Vec<Matrix<f32>> {
+        if self.previous_mask.is_none() {
+            println!("Dropout Error - Previous mask is none.");
+            return vec![];
+        }
+
+        vec![previous_gradients[0].element_mult(self.previous_mask.clone().unwrap())]
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.training = training;
+    }
+
+    fn is_training(&self) -> bool {
+        self.training
+    }
+}
+
+
+// Layer normalization over each row: y = gamma * (x - mean) / sqrt(variance + epsilon) + beta.
+// gamma and beta are (1 x d_model) vectors broadcast over every row, so any sequence length can be normalized.
+pub struct LayerNorm {
+    pub weights: Parameter,
+    pub biases: Parameter,
+    pub epsilon: f32,
+
+    pub previous_variances: Option<Matrix<f32>>,
+    pub previous_finals: Option<Matrix<f32>>,
+    pub training: bool
+}
+
+impl LayerNorm {
+    // Starts as the identity transform (weights of one, biases of zero), as with Keras' LayerNormalization.
+    pub fn new(d_model: usize, epsilon: f32) -> LayerNorm {
+        LayerNorm {
+            weights: Parameter::new(Matrix::new(1, d_model, 1.0)),
+            biases: Parameter::new(Matrix::new(1, d_model, 0.0)),
+            epsilon: epsilon,
+
+            previous_variances: None,
+            previous_finals: None,
+            training: true
+        }
+    }
+}
+
+impl Layer for LayerNorm {
+    fn forward(&mut self, inputs: Vec<Matrix<f32>>) -> Vec<Matrix<f32>> {
+        let input: Matrix<f32> = inputs[0].clone();
+        let mut means: Matrix<f32> = Matrix::new(input.rows, 1, 0.0);
+
+        for row in 0..input.rows {
+            for col in 0..input.cols {
+                means.set(row, 0, means.get(row, 0) + input.get(row, col));
+            }
+        }
+
+        means = means.clone() / input.cols as f32;
+
+        let mut variances: Matrix<f32> = Matrix::new(input.rows, 1, 0.0);
+
+        for row in 0..input.rows {
+            for col in 0..input.cols {
+                variances.set(row, 0, variances.get(row, 0) + (input.get(row, col) - means.get(row, 0)).powf(2.0));
+            }
+        }
+
+        variances = variances.clone() / input.cols as f32;
+
+        let mut normalized_values: Matrix<f32> = Matrix::new(input.rows, input.cols, 0.0);
+
+        for row in 0..input.rows {
+            for col in 0..input.cols {
+                normalized_values.set(row, col, (input.get(row, col) - means.get(row, 0)) / (variances.get(row, 0) + self.epsilon).sqrt());
+            }
+        }
+
+        let result: Matrix<f32> = normalized_values.mult_row_vector(self.weights.value()).add_row_vector(self.biases.value());
+
+        if self.training {
+            self.previous_variances = Some(variances);
+            self.previous_finals = Some(normalized_values);
+        }
+
+        vec![result]
+    }
+
+    fn calculate_gradients(&mut self, previous_gradients: Vec<Matrix<f32>>) -> Vec<Matrix<f32>> {
+        if self.previous_variances.is_none() {
+            println!("LayerNorm Error - Previous variances are none.");
+            return vec![];
+        } else if self.previous_finals.is_none() {
+            println!("LayerNorm Error - Previous finals are none.");
+            return vec![];
+        }
+
+        let variances: Matrix<f32> = self.previous_variances.clone().unwrap();
+        let finals: Matrix<f32> = self.previous_finals.clone().unwrap();
+
+        self.weights.accumulate_gradient(finals.element_mult(previous_gradients[0].clone()).col_sums());
+        self.biases.accumulate_gradient(previous_gradients[0].col_sums());
+
+        let final_gradients: Matrix<f32> = previous_gradients[0].mult_row_vector(self.weights.value());
+
+        let mut input_gradients: Matrix<f32> = Matrix::new(finals.rows, finals.cols, 0.0);
+
+        // dx = (dx_hat - mean(dx_hat) - x_hat * mean(dx_hat * x_hat)) / sqrt(variance + epsilon)
+        for row in 0..input_gradients.rows {
+            let mut final_summation: f32 = 0.0;
+            let mut multiplied_final_summation: f32 = 0.0;
+
+            for col in 0..input_gradients.cols {
+                final_summation += final_gradients.get(row, col);
+                multiplied_final_summation += final_gradients.get(row, col) * finals.get(row, col);
+            }
+
+            let final_mean: f32 = final_summation / input_gradients.cols as f32;
+            let multiplied_final_mean: f32 = multiplied_final_summation / input_gradients.cols as f32;
+
+            for col in 0..input_gradients.cols {
+                input_gradients.set(row, col, (final_gradients.get(row, col) - final_mean - finals.get(row, col) * multiplied_final_mean) / (variances.get(row, 0) + self.epsilon).sqrt());
+            }
+        }
+
+        vec![input_gradients]
+    }
+
+    fn parameters(&self) -> Vec<(String, &Parameter)> {
+        vec![("weights".to_string(), &self.weights), ("biases".to_string(), &self.biases)]
+    }
+
+    fn parameters_mut(&mut self) -> Vec<(String, &mut Parameter)> {
+        vec![("weights".to_string(), &mut self.weights), ("biases".to_string(), &mut self.biases)]
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.training = training;
+    }
+
+    fn is_training(&self) -> bool {
+        self.training
+    }
+}
+
+
+pub const DEFAULT_NORM_EPSILON: f32 = 1e-6;
+
+// Root mean square normalization, as used by BitNet: y = gain * x / sqrt(mean(x^2) + epsilon) for each row.
+// The (1 x d_model) gain is broadcast over every row, so any sequence length can be normalized.
+pub struct RMSNorm {
+    pub weights: Parameter,
+    pub epsilon: f32,
+
+    pub previous_rms: Option<Matrix<f32>>,
+    pub previous_finals: Option<Matrix<f32>>,
+    pub training: bool
+}
+
+impl RMSNorm {
+    pub fn new(d_model: usize, epsilon: f32) -> RMSNorm {
+        RMSNorm {
+            weights: Parameter::new(Matrix::new(1, d_model, 1.0)),
+            epsilon: epsilon,
+
+            previous_rms: None,
+            previous_finals: None,
+            training: true
+        }
+    }
+}
+
+impl Layer for RMSNorm {
+    fn forward(&mut self, inputs: Vec<Matrix<f32>>) -> Vec<Matrix<f32>> {
+        let input: Matrix<f32> = inputs[0].clone();
+        let mut rms: Matrix<f32> = Matrix::new(input.rows, 1, 0.0);
+        let mut normalized_values: Matrix<f32> = Matrix::new(input.rows, input.cols, 0.0);
+
+        for row in 0..input.rows {
+            for col in 0..input.cols {
+                rms.set(row, 0, rms.get(row, 0) + input.get(row, col).powf(2.0));
+            }
+
+            rms.set(row, 0, (rms.get(row, 0) / input.cols as f32 + self.epsilon).sqrt());
+
+            for col in 0..input.cols {
+                normalized_values.set(row, col, input.get(row, col) / rms.get(row, 0));
+            }
+        }
+
+        let result: Matrix<f32> = normalized_values.mult_row_vector(self.weights.value());
+
+        if self.training {
+            self.previous_rms = Some(rms);
+            self.previous_finals = Some(normalized_values);
+        }
+
+        vec![result]
+    }
+
+    fn calculate_gradients(&mut self, previous_gradients: Vec<Matrix<f32>>) -> Vec<Matrix<f32>> {
+        if self.previous_rms.is_none() || self.previous_finals.is_none() {
+            println!("RMSNorm Error - Previous values are none.");
+            return vec![];
+        }
+
+        let rms: Matrix<f32> = self.previous_rms.clone().unwrap();
+        let finals: Matrix<f32> = self.previous_finals.clone().unwrap();
+
+        self.weights.accumulate_gradient(finals.element_mult(previous_gradients[0].clone()).col_sums());
+
+        let final_gradients: Matrix<f32> = previous_gradients[0].mult_row_vector(self.weights.value());
+        let mut input_gradients: Matrix<f32> = Matrix::new(finals.rows, finals.cols, 0.0);
+
+        // dx = (dx_hat - x_hat * mean(dx_hat * x_hat)) / rms
+        for row in 0..finals.rows {
+            let mut multiplied_final_summation: f32 = 0.0;
+
+            for col in 0..finals.cols {
+                multiplied_final_summation += final_gradients.get(row, col) * finals.get(row, col);
+            }
+
+            let multiplied_final_mean: f32 = multiplied_final_summation / finals.cols as f32;
+
+            for col in 0..finals.cols {
+                input_gradients.set(row, col, (final_gradients.get(row, col) - finals.get(row, col) * multiplied_final_mean) / rms.get(row, 0));
+            }
+        }
+
+        vec![input_gradients]
+    }
+
+    fn parameters(&self) -> Vec<(String, &Parameter)> {
+        vec![("weights".to_string(), &self.weights)]
+    }
+
+    fn parameters_mut(&mut self) -> Vec<(String, &mut Parameter)> {
+        vec![("weights".to_string(), &mut self.weights)]
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.training = training;
+    }
+
+    fn is_training(&self) -> bool {
+        self.training
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Normalization {
+    LayerNorm,
+    RMSNorm
+}
+
+// Builds the normalization layer used inside decoder blocks and before the LLM's output projection.
+pub fn normalization_layer(normalization: Normalization, d_model: usize) -> Box<dyn Layer> {
+    match normalization {
+        Normalization::LayerNorm => Box::new(LayerNorm::new(d_model, DEFAULT_NORM_EPSILON)),
+        Normalization::RMSNorm => Box::new(RMSNorm::new(d_model, DEFAULT_NORM_EPSILON))
+    }
+}
+
+
+// Token embedding lookup. The input is a (sequence_length x 1) column of token indices, and each output row
+// is the embedding of that token. Backward only touches the rows of the tokens that were looked up.
+pub struct Embedding {
+    pub vocabulary_size: usize,
+    pub weights: Parameter,
+
+    pub previous_indices: Option<Vec<usize>>,
+    pub training: bool
+}
+
+impl Embedding {
+    pub fn new(vocabulary_size: usize, d_model: usize, parameter_min: f32, parameter_max: f32) -> Embedding {
+        Embedding {
+            vocabulary_size: vocabulary_size,
+            weights: Parameter::new(generate_parameter(vocabulary_size, d_model, parameter_min, parameter_max)),
+
+            previous_indices: None,
+            training: true
+        }
+    }
+
+    pub fn indices_to_matrix(indices: &[usize]) -> Matrix<f32> {
+        Matrix {
+            rows: indices.len(),
+            cols: 1,
+            data: indices.iter().map(|index| *index as f32).collect()
+        }
+    }
+}
+
+impl Layer for Embedding {
+    fn forward(&mut self, inputs: Vec<Matrix<f32>>) -> Vec<Matrix<f32>> {
+        let indices: Vec<usize> = inputs[0].data.iter().map(|index| index.round() as usize).collect();
+
+        if let Some(index) = indices.iter().find(|index| **index >= self.vocabulary_size) {
+            println!("Embedding Error - Token index {} is outside the vocabulary of size {}.", index, self.vocabulary_size);
+            return vec![];
+        }
+
+        let weights: Ref<'_, ParameterData> = self.weights.borrow();
+        let d_model: usize = weights.value.cols;
+        let mut result: Matrix<f32> = Matrix::new(indices.len(), d_model, 0.0);
+
+        for (row, index) in indices.iter().enumerate() {
+            for col in 0..d_model {
+                result.set(row, col, weights.value.get(*index, col));
+            }
+        }
+
+        drop(weights);
+
+        if self.training {
+            self.previous_indices = Some(indices);
+        }
+
+        vec![result]
+    }
+
+    fn calculate_gradients(&mut self, previous_gradients: Vec<Matrix<f32>>) -> Vec<Matrix<f32>> {
+        if self.previous_indices.is_none() {
+            println!("Embedding Error - Previous indices are none.");
+            return vec![];
+        }
+
+        let indices: Vec<usize> = self.previous_indices.clone().unwrap();
+        let mut weights: RefMut<'_, ParameterData> = self.weights.borrow_mut();
+        let d_model: usize = weights.value.cols;
+
+        if weights.gradient.is_none() {
+            weights.gradient = Some(Matrix::new(self.vocabulary_size, d_model, 0.0));
+        }
+
+        let weights_gradients: &mut Matrix<f32> = weights.gradient.as_mut().unwrap();
+
+        for (row, index) in indices.iter().enumerate() {
+            for col in 0..d_model {
+                weights_gradients.set(*index, col, weights_gradients.get(*index, col) + previous_gradients[0].get(row, col));
+            }
+        }
+
+        // Token indices are not differentiable.
+        vec![Matrix::new(indices.len(), 1, 0.0)]
+    }
+
+    fn parameters(&self) -> Vec<(String, &Parameter)> {
+        vec![("weights".to_string(), &self.weights)]
+    }
+
+    fn parameters_mut(&mut self) -> Vec<(String, &mut Parameter)> {
+        vec![("weights".to_string(), &mut self.weights)]
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.training = training;
+    }
+
+    fn is_training(&self) -> bool {
+        self.training
+    }
+}
+
+
+// Learned absolute positions, matching `positionalEmbedding` in the Python model. Row i of the
+// (sequence_length x d_model) input has the embedding of position i added to it. With `sequence_length` set, the
+// rows hold a batch of consecutive sequences of that length, and positions restart at zero for each of them.
+pub struct PositionalEmbedding {
+    pub max_positions: usize,
+    pub weights: Parameter,
+    pub sequence_length: Option<usize>,
+
+    pub previous_row_count: Option<usize>,
+    pub previous_sequence_length: Option<usize>,
+    pub training: bool
+}
+
+impl PositionalEmbedding {
+    pub fn new(max_positions: usize, d_model: usize, parameter_min: f32, parameter_max: f32) -> PositionalEmbedding {
+        PositionalEmbedding {
+            max_positions: max_positions,
+            weights: Parameter::new(generate_parameter(max_positions, d_model, parameter_min, parameter_max)),
+            sequence_length: None,
+
+            previous_row_count: None,
+            previous_sequence_length: None,
+            training: true
+        }
+    }
+
+    // Adds the embeddings of positions `start_position..` to the rows of `input`, for cached generation.
+    pub fn add_positions(&self, input: &Matrix<f32>, start_position: usize) -> Matrix<f32> {
+        if start_position + input.rows > self.max_positions {
+            println!("Positional Embedding Error - Position {} exceeds the maximum of {} positions.", start_position + input.rows, self.max_positions);
+            return input.clone();
+        }
+
+        let weights: Matrix<f32> = self.weights.value();
+        let mut result: Matrix<f32> = input.clone();
+
+        for row in 0..input.rows {
+            for col in 0..input.cols {
+                result.set(row, col, input.get(row, col) + weights.get(start_position + row, col));
+            }
+        }
+
+        result
+    }
+}
+
+impl Layer for PositionalEmbedding {
+    fn forward(&mut self, inputs: Vec<Matrix<f32>>) -> Vec<Matrix<f32>> {
+        let input: Matrix<f32> = inputs[0].clone();
+        let sequence_length: usize = self.sequence_length.unwrap_or(input.rows);
+
+        if sequence_length > self.max_positions {
+            println!("Positional Embedding Error - Sequence length {} exceeds the maximum of {} positions.", sequence_length, self.max_positions);
+            return vec![input];
+        }
+
+        let weights: Matrix<f32> = self.weights.value();
+        let mut result: Matrix<f32> = input.clone();
+
+        for row in 0..input.rows {
+            for col in 0..input.cols {
+                result.set(row, col, input.get(row, col) + weights.get(row % sequence_length, col));
+            }
+        }
+
+        if self.training {
+            self.previous_row_count = Some(input.rows);
+            self.previous_sequence_length = Some(sequence_length);
+        }
+
+        vec![result]
+    }
+
+    fn calculate_gradients(&mut self, previous_gradients: Vec<Matrix<f32>>) -> Vec<Matrix<f32>> {
+        if self.previous_row_count.is_none() || self.previous_sequence_length.is_none() {
+            println!("Positional Embedding Error - Previous sequence length is none.");
+            return vec![];
+        }
+
+        let row_count: usize = self.previous_row_count.unwrap();
+        let sequence_length: usize = self.previous_sequence_length.unwrap();
+        let mut weights: RefMut<'_, ParameterData> = self.weights.borrow_mut();
+        let d_model: usize = weights.value.cols;
+
+        if weights.gradient.is_none() {
+            weights.gradient = Some(Matrix::new(self.max_positions, d_model, 0.0));
+        }
+
+        let weights_gradients: &mut Matrix<f32> = weights.gradient.as_mut().unwrap();
+
+        // Every sequence in a batch adds onto the gradients of the same positions.
+        for row in 0..row_count {
+            let position: usize = row % sequence_length;
+
+            for col in 0..d_model {
+                weights_gradients.set(position, col, weights_gradients.get(position, col) + previous_gradients[0].get(row, col));
+            }
+        }
+
+        vec![previous_gradients[0].clone()]
+    }
+
+    fn parameters(&self) -> Vec<(String, &Parameter)> {
+        vec![("weights".to_string(), &self.weights)]
+    }
+
+    fn parameters_mut(&mut self) -> Vec<(String, &mut Parameter)> {
+        vec![("weights".to_string(), &mut self.weights)]
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.training = training;
+    }
+
+    fn is_training(&self) -> bool {
+        self.training
+    }
+}
+
+
+// Multiplies its input by the transpose of a weight matrix shared with another layer, e.g. the LLM's output
+// projection, which reuses the (vocabulary_size x d_model) token embedding weights. Gradients are added onto the
+// shared parameter, and since its owner already reports it, this layer lists no parameters of its own.
+pub struct TiedProjection {
+    pub weights: Parameter,
+
+    pub previous_input: Option<Matrix<f32>>,
+    pub training: bool
+}
+
+impl TiedProjection {
+    pub fn new(weights: Parameter) -> TiedProjection {
+        TiedProjection {
+            weights: weights,
+
+            previous_input: None,
+            training: true
+        }
+    }
+}
+
+impl Layer for TiedProjection {
+    fn forward(&mut self, inputs: Vec<Matrix<f32>>) -> Vec<Matrix<f32>> {
+        let input: Matrix<f32> = inputs[0].clone();
+
+        if self.training {
+            self.previous_input = Some(input.clone());
+        }
+
+        vec![input * self.weights.value().transpose()]
+    }
+
+    fn calculate_gradients(&mut self, previous_gradients: Vec<Matrix<f32>>) -> Vec<Matrix<f32>> {
+        if self.previous_input.is_none() {
+            println!("Tied Projection Error - Previous input is none.");
+            return vec![];
+        }
+
+        self.weights.accumulate_gradient(previous_gradients[0].transpose() * self.previous_input.clone().unwrap());
+
+        vec![previous_gradients[0].clone() * self.weights.value()]
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.training = training;
+    }
+
+    fn is_training(&self) -> bool {
+        self.training
+    }
+}
+
+
+// Added to the attention score of every masked position, as in `LLM.generate_mask` in the Python model.
+pub const MASK_VALUE: f32 = -1e10;
+
+
+// ALiBi slopes for each head: the geometric sequence 2^(-8 / n), 2^(-16 / n), ... for n heads. Head counts
+// that are not a power of two take the slopes of the closest smaller power of two, then every other slope
+// of the next power of two, following the ALiBi paper.
+pub fn alibi_slopes(head_count: usize) -> Vec<f32> {
+    fn power_of_two_slopes(head_count: usize) -> Vec<f32> {
+        (1..=head_count).map(|head| 2.0_f32.powf(-8.0 * head as f32 / head_count as f32)).collect()
+    }
+
+    if head_count.is_power_of_two() {
+        return power_of_two_slopes(head_count);
+    }
+
+    let closest_power: usize = 1 << head_count.ilog2();
+    let mut slopes: Vec<f32> = power_of_two_slopes(closest_power);
+
+    slopes.extend(power_of_two_slopes(2 * closest_power).into_iter().step_by(2).take(head_count - closest_power));
+    slopes
+}
+
+
+// Attention for a single head. With `window` set, each query only sees the `window` most recent keys up to and
+// including its own position (sliding-window attention), in addition to any causal or padding mask.
+// With `sequence_length` set, the rows hold a batch of consecutive sequences of that length and each query only
+// sees keys from its own sequence. The untiled path then attends within each sequence's block of rows on its own,
+// so a batch of B sequences of length T costs B * T^2 rather than (B * T)^2.
+// With `tile_size` set, queries and keys are processed in (tile_size x tile_size)
+// blocks with an online softmax, as in FlashAttention: neither the score matrix nor the mask is ever built in
+// full, and only the output and each row's log-sum-exp are kept for a backward pass that recomputes the scores.
+// Attention dropout draws each tile's mask from a per-call seed, which the backward pass replays.
+// A query row that every key is masked from (e.g. a first query whose only visible key is padding) outputs zero.
+pub struct ScaledDotProduct {
+    d_model: usize,
+    pub causal: bool,
+    pub key_padding: Option<Vec<bool>>,
+    pub alibi_slope: Option<f32>,
+    pub window: Option<usize>,
+    pub sequence_length: Option<usize>,
+    pub dropout: Option<Dropout>,
+    pub tile_size: Option<usize>,
+    previous_inputs: Option<Vec<Matrix<f32>>>,
+    previous_masks: Option<Vec<Matrix<f32>>>,
+    previous_statistics: Option<Vec<Matrix<f32>>>,
+    previous_sequence_length: Option<usize>,
+    previous_tile_size: Option<usize>,
+    previous_dropout_seed: Option<u64>,
+    training: bool
+}
+
+impl ScaledDotProduct {
+    pub fn new(d_model: usize) -> ScaledDotProduct {
+        ScaledDotProduct {
+            d_model: d_model,
+            causal: false,
+            key_padding: None,
+            alibi_slope: None,
+            window: None,
+            sequence_length: None,
+            dropout: None,
+            tile_size: None,
+            previous_inputs: None,
+            previous_masks: None,
+            previous_statistics: None,
+            previous_sequence_length: None,
+            previous_tile_size: None,
+            previous_dropout_seed: None,
+            training: true
+        }
+    }
+
+    fn is_masked(value: f32) -> bool {
+        value <= MASK_VALUE / 2.0
+    }
+
+    // Softmax of the scores, with the rows that every key is masked from set to zero rather than spread evenly
+    // over the masked keys.
+    fn probabilities(&self, q: &Matrix<f32>, k: &Matrix<f32>, mask: &Matrix<f32>) -> Matrix<f32> {
+        let mut probabilities: Matrix<f32> = self.scores(q, k, mask).row_softmax();
+
+        for row in 0..mask.rows {
+            if (0..mask.cols).all(|col| ScaledDotProduct::is_masked(mask.get(row, col))) {
+                for col in 0..mask.cols {
+                    probabilities.set(row, col, 0.0);
+                }
+            }
+        }
+
+        probabilities
+    }
+
+    // The dropout mask of the tile starting at query `query_start` and key `key_start`, from the call's seed.
+    fn tile_dropout_mask(&self, dropout_seed: Option<u64>, query_start: usize, key_start: usize, rows: usize, cols: usize) -> Option<Matrix<f32>> {
+        let (dropout, seed) = (self.dropout.as_ref()?, dropout_seed?);
+        Some(dropout.mask_from_seed(rows, cols, seed ^ ((query_start as u64) << 32) ^ key_start as u64))
+    }
+
+    // The block of the combined mask covering queries `rows` and keys `cols`: the causal, sliding window, sequence and
+    // key padding masks, the ALiBi distance bias and any extra additive mask passed to `forward`.
+    fn mask_tile(&self, rows: Range<usize>, cols: Range<usize>, query_count: usize, key_count: usize, extra_mask: Option<&Matrix<f32>>, sequence_length: Option<usize>) -> Matrix<f32> {
+        let mut mask: Matrix<f32> = Matrix::new(rows.len(), cols.len(), 0.0);
+        let offset: usize = key_count.saturating_sub(query_count);
+
+        for row in rows.clone() {
+            for col in cols.clone() {
+                let future: bool = self.causal && col > row + offset;
+                let outside_window: bool = self.window.is_some_and(|window| col + window <= row + offset);
+                let other_sequence: bool = sequence_length.is_some_and(|length| col / length != (row + offset) / length);
+                let padded: bool = self.key_padding.as_ref().is_some_and(|padding| padding[col]);
+                let mut value: f32 = if future || outside_window || other_sequence || padded { MASK_VALUE } else { 0.0 };
+
+                if let Some(slope) = self.alibi_slope {
+                    value -= slope * ((row + offset) as f32 - col as f32).abs();
+                }
+
+                if let Some(extra_mask) = extra_mask {
+                    value += extra_mask.get(row, col);
+                }
+
+                mask.set(row - rows.start, col - cols.start, value);
+            }
+        }
+
+        mask
+    }
+
+    fn scores(&self, q: &Matrix<f32>, k: &Matrix<f32>, mask: &Matrix<f32>) -> Matrix<f32> {
+        q.clone() * k.transpose() / (self.d_model as f32).sqrt() + mask.clone()
+    }
+
+    // The (queries, keys) row ranges of each sequence, in order. Together they cover every query and every key, and
+    // a sequence whose keys all come before the first query has an empty query range. Without `sequence_length` all
+    // the rows are one sequence.
+    fn sequence_blocks(query_count: usize, key_count: usize, sequence_length: Option<usize>) -> Vec<(Range<usize>, Range<usize>)> {
+        let length: usize = match sequence_length {
+            Some(length) if length > 0 => length,
+            _ => return vec![(0..query_count, 0..key_count)]
+        };
+
+        let offset: usize = key_count.saturating_sub(query_count);
+        let sequence_count: usize = (query_count + offset).max(key_count).div_ceil(length).max(1);
+
+        (0..sequence_count).map(|sequence| {
+            let (start, end) = (sequence * length, (sequence + 1) * length);
+            let queries: Range<usize> = start.saturating_sub(offset).min(query_count)..end.saturating_sub(offset).min(query_count);
+
+            (queries, start.min(key_count)..end.min(key_count))
+        }).collect()
+    }
+
+    // Runs `dropout` forward, or backward through its stored mask, over every sequence's probability block at once,
+    // so that a single mask covers the whole batch.
+    fn dropout_blocks(dropout: &mut Dropout, blocks: Vec<Matrix<f32>>, backward: bool) -> Vec<Matrix<f32>> {
+        let shapes: Vec<(usize, usize)> = blocks.iter().map(|block| (block.rows, block.cols)).collect();
+        let data: Vec<f32> = blocks.into_iter().flat_map(|block| block.data).collect();
+        let flattened: Matrix<f32> = Matrix { rows: 1, cols: data.len(), data: data };
+
+        let result: Matrix<f32> = if backward {
+            dropout.calculate_gradients(vec![flattened])[0].clone()
+        } else {
+            dropout.forward(vec![flattened])[0].clone()
+        };
+
+        let mut start: usize = 0;
+
+        shapes.into_iter().map(|(rows, cols)| {
+            let block: Matrix<f32> = Matrix { rows: rows, cols: cols, data: result.data[start..start + rows * cols].to_vec() };
+            start += rows * cols;
+            block
+        }).collect()
+    }
+
+    // Key tiles entirely after the last query of a causal tile, entirely before the window of its first query, or
+    // entirely outside the sequences of its queries are fully masked and can be skipped.
+    fn skips_key_tile(&self, queries: Range<usize>, keys: Range<usize>, query_count: usize, key_count: usize, sequence_length: Option<usize>) -> bool {
+        let offset: usize = key_count.saturating_sub(query_count);
+
+        let future: bool = self.causal && keys.start > queries.end - 1 + offset;
+        let outside_window: bool = self.window.is_some_and(|window| keys.end - 1 + window <= queries.start + offset);
+        let other_sequences: bool = sequence_length.is_some_and(|length| {
+            (keys.end - 1) / length < (queries.start + offset) / length || keys.start / length > (queries.end - 1 + offset) / length
+        });
+
+        future || outside_window || other_sequences
+    }
+
+    // Returns the attention output for `inputs` as given to `forward`, and a (queries x 1) column of each row's log-sum-exp.
+    // Rows that every key is masked from get an output of zero and a log-sum-exp of infinity, so that the backward
+    // pass recomputes probabilities of zero for them.
+    fn forward_tiled(&self, inputs: &[Matrix<f32>], tile_size: usize, sequence_length: Option<usize>, dropout_seed: Option<u64>) -> (Matrix<f32>, Matrix<f32>) {
+        let (q, k, v) = (&inputs[0], &inputs[1], &inputs[2]);
+        let extra_mask: Option<&Matrix<f32>> = inputs.get(3);
+        let mut output_tiles: Vec<Matrix<f32>> = vec![];
+        let mut logsumexp_tiles: Vec<Matrix<f32>> = vec![];
+
+        for query_start in (0..q.rows).step_by(tile_size) {
+            let query_end: usize = (query_start + tile_size).min(q.rows);
+            let q_tile: Matrix<f32> = q.slice_rows(query_start, query_end);
+
+            // Running row maxima and softmax denominators, rescaled whenever a later tile raises the maximum.
+            let mut maxima: Vec<f32> = vec![f32::NEG_INFINITY; q_tile.rows];
+            let mut totals: Vec<f32> = vec![0.0; q_tile.rows];
+            let mut visible: Vec<bool> = vec![false; q_tile.rows];
+            let mut accumulator: Matrix<f32> = Matrix::new(q_tile.rows, v.cols, 0.0);
+
+            for key_start in (0..k.rows).step_by(tile_size) {
+                let key_end: usize = (key_start + tile_size).min(k.rows);
+
+                if self.skips_key_tile(query_start..query_end, key_start..key_end, q.rows, k.rows, sequence_length) {
+                    continue;
+                }
+
+                let mask: Matrix<f32> = self.mask_tile(query_start..query_end, key_start..key_end, q.rows, k.rows, extra_mask, sequence_length);
+                let scores: Matrix<f32> = self.scores(&q_tile, &k.slice_rows(key_start, key_end), &mask);
+                let mut probabilities: Matrix<f32> = Matrix::new(scores.rows, scores.cols, 0.0);
+
+                for row in 0..scores.rows {
+                    visible[row] |= (0..mask.cols).any(|col| !ScaledDotProduct::is_masked(mask.get(row, col)));
+
+                    let mut maximum: f32 = maxima[row];
+
+                    for col in 0..scores.cols {
+                        maximum = maximum.max(scores.get(row, col));
+                    }
+
+                    let correction: f32 = (maxima[row] - maximum).exp();
+                    totals[row] *= correction;
+
+                    for col in 0..scores.cols {
+                        let probability: f32 = (scores.get(row, col) - maximum).exp();
+                        probabilities.set(row, col, probability);
+                        totals[row] += probability;
+                    }
+
+                    for col in 0..accumulator.cols {
+                        accumulator.set(row, col, accumulator.get(row, col) * correction);
+                    }
+
+                    maxima[row] = maximum;
+                }
+
+                // The totals above are taken before dropout, which only scales what reaches the output.
+                if let Some(dropout_mask) = self.tile_dropout_mask(dropout_seed, query_start, key_start, scores.rows, scores.cols) {
+                    probabilities = probabilities.element_mult(dropout_mask);
+                }
+
+                accumulator = accumulator + probabilities * v.slice_rows(key_start, key_end);
+            }
+
+            let mut logsumexp: Matrix<f32> = Matrix::new(q_tile.rows, 1, 0.0);
+
+            for row in 0..q_tile.rows {
+                if !visible[row] {
+                    for col in 0..accumulator.cols {
+                        accumulator.set(row, col, 0.0);
+                    }
+
+                    logsumexp.set(row, 0, f32::INFINITY);
+                    continue;
+                }
+
+                for col in 0..accumulator.cols {
+                    accumulator.set(row, col, accumulator.get(row, col) / totals[row]);
+                }
+
+                logsumexp.set(row, 0, maxima[row] + totals[row].ln());
+            }
+
+            output_tiles.push(accumulator);
+            logsumexp_tiles.push(logsumexp);
+        }
+
+        (Matrix::concat_rows(output_tiles), Matrix::concat_rows(logsumexp_tiles))
+    }
+
+    // Recomputes each block of probabilities from the stored log-sum-exp, P = exp(S - L), and each dropout mask from
+    // the stored seed.
+    fn calculate_tiled_gradients(&self, output_gradients: &Matrix<f32>, tile_size: usize) -> Vec<Matrix<f32>> {
+        let inputs: &Vec<Matrix<f32>> = self.previous_inputs.as_ref().unwrap();
+        let statistics: &Vec<Matrix<f32>> = self.previous_statistics.as_ref().unwrap();
+
+        let (q, k, v) = (&inputs[0], &inputs[1], &inputs[2]);
+        let extra_mask: Option<&Matrix<f32>> = inputs.get(3);
+        let (output, logsumexp) = (&statistics[0], &statistics[1]);
+        let sequence_length: Option<usize> = self.previous_sequence_length;
+        let dropout_seed: Option<u64> = self.previous_dropout_seed;
+        let scale: f32 = 1.0 / (self.d_model as f32).sqrt();
+
+        // sum_k(dP_k * P_k) from the softmax Jacobian equals the row sum of dO * O, with or without dropout.
+        let mut deltas: Vec<f32> = vec![0.0; q.rows];
+
+        for (row, delta) in deltas.iter_mut().enumerate() {
+            for col in 0..output.cols {
+                *delta += output_gradients.get(row, col) * output.get(row, col);
+            }
+        }
+
+        let key_starts: Vec<usize> = (0..k.rows).step_by(tile_size).collect();
+        let mut k_gradient_tiles: Vec<Matrix<f32>> = key_starts.iter().map(|start| Matrix::new((start + tile_size).min(k.rows) - start, k.cols, 0.0)).collect();
+        let mut v_gradient_tiles: Vec<Matrix<f32>> = key_starts.iter().map(|start| Matrix::new((start + tile_size).min(k.rows) - start, v.cols, 0.0)).collect();
+        let mut q_gradient_tiles: Vec<Matrix<f32>> = vec![];
+
+        for query_start in (0..q.rows).step_by(tile_size) {
+            let query_end: usize = (query_start + tile_size).min(q.rows);
+            let q_tile: Matrix<f32> = q.slice_rows(query_start, query_end);
+            let output_gradient_tile: Matrix<f32> = output_gradients.slice_rows(query_start, query_end);
+            let mut q_gradient_tile: Matrix<f32> = Matrix::new(q_tile.rows, q.cols, 0.0);
+
+            for (tile_index, &key_start) in key_starts.iter().enumerate() {
+                let key_end: usize = (key_start + tile_size).min(k.rows);
+
+                if self.skips_key_tile(query_start..query_end, key_start..key_end, q.rows, k.rows, sequence_length) {
+                    continue;
+                }
+
+                let k_tile: Matrix<f32> = k.slice_rows(key_start, key_end);
+                let v_tile: Matrix<f32> = v.slice_rows(key_start, key_end);
+
+                let mask: Matrix<f32> = self.mask_tile(query_start..query_end, key_start..key_end, q.rows, k.rows, extra_mask, sequence_length);
+                let scores: Matrix<f32> = self.scores(&q_tile, &k_tile, &mask);
+
+                let mut probabilities: Matrix<f32> = Matrix::new(scores.rows, scores.cols, 0.0);
+
+                for row in 0..scores.rows {
+                    for col in 0..scores.cols {
+                        probabilities.set(row, col, (scores.get(row, col) - logsumexp.get(query_start + row, 0)).exp());
+                    }
+                }
+
+                let mut probability_gradients: Matrix<f32> = output_gradient_tile.clone() * v_tile.transpose();
+                let mut dropped_probabilities: Matrix<f32> = probabilities.clone();
+
+                if let Some(dropout_mask) = self.tile_dropout_mask(dropout_seed, query_start, key_start, scores.rows, scores.cols) {
+                    probability_gradients = probability_gradients.element_mult(dropout_mask.clone());
+                    dropped_probabilities = dropped_probabilities.element_mult(dropout_mask);
+                }
+
+                let mut score_gradients: Matrix<f32> = Matrix::new(scores.rows, scores.cols, 0.0);
+
+                for row in 0..scores.rows {
+                    for col in 0..scores.cols {
+                        score_gradients.set(row, col, probabilities.get(row, col) * (probability_gradients.get(row, col) - deltas[query_start + row]));
+                    }
+                }
+
+                v_gradient_tiles[tile_index] = v_gradient_tiles[tile_index].clone() + dropped_probabilities.transpose() * output_gradient_tile.clone();
+                k_gradient_tiles[tile_index] = k_gradient_tiles[tile_index].clone() + score_gradients.transpose() * q_tile.clone() * scale;
+                q_gradient_tile = q_gradient_tile + score_gradients * k_tile * scale;
+            }
+
+            q_gradient_tiles.push(q_gradient_tile);
+        }
+
+        vec![Matrix::concat_rows(q_gradient_tiles), Matrix::concat_rows(k_gradient_tiles), Matrix::concat_rows(v_gradient_tiles)]
+    }
+}
+
+impl Layer for ScaledDotProduct {
+    // Inputs are [q, k, v] with an optional fourth additive mask of shape (q rows x k rows).
+    fn forward(&mut self, inputs: Vec<Matrix<f32>>) -> Vec<Matrix<f32>> {
+        let q: Matrix<f32> = inputs[0].clone();
+        let k: Matrix<f32> = inputs[1].clone();
+        let v: Matrix<f32> = inputs[2].clone();
+
+        // Padding is set once per head and outlives the call it was meant for, so check it still matches the keys.
+        if let Some(padding) = self.key_padding.as_ref() && padding.len() != k.rows {
+            println!("Scaled Dot Product Error - Key padding covers {} keys but {} keys were given.", padding.len(), k.rows);
+            return vec![Matrix::new(q.rows, v.cols, 0.0)];
+        }
+
+        let sequence_length: Option<usize> = self.sequence_length;
+
+        if let Some(tile_size) = self.tile_size {
+            let dropout_seed: Option<u64> = self.dropout.as_mut().filter(|dropout| dropout.is_training()).map(|dropout| dropout.next_seed());
+            let (output, logsumexp) = self.forward_tiled(&inputs, tile_size, sequence_length, dropout_seed);
+
+            if self.training {
+                self.previous_inputs = Some(inputs);
+                self.previous_masks = None;
+                self.previous_statistics = Some(vec![output.clone(), logsumexp]);
+                self.previous_sequence_length = sequence_length;
+                self.previous_tile_size = Some(tile_size);
+                self.previous_dropout_seed = dropout_seed;
+            }
+
+            return vec![output];
+        }
+
+        // Each sequence only sees its own keys, so its block of rows attends on its own.
+        let blocks: Vec<(Range<usize>, Range<usize>)> = ScaledDotProduct::sequence_blocks(q.rows, k.rows, sequence_length);
+        let mut masks: Vec<Matrix<f32>> = vec![];
+        let mut probabilities: Vec<Matrix<f32>> = vec![];
+
+        for (queries, keys) in blocks.iter() {
+            let mask: Matrix<f32> = self.mask_tile(queries.clone(), keys.clone(), q.rows, k.rows, inputs.get(3), sequence_length);
+
+            probabilities.push(self.probabilities(&q.slice_rows(queries.start, queries.end), &k.slice_rows(keys.start, keys.end), &mask));
+            masks.push(mask);
+        }
+
+        if let Some(dropout) = self.dropout.as_mut() {
+            probabilities = ScaledDotProduct::dropout_blocks(dropout, probabilities, false);
+        }
+
+        let results: Vec<Matrix<f32>> = blocks.iter().zip(probabilities).map(|((_, keys), block_probabilities)| {
+            block_probabilities * v.slice_rows(keys.start, keys.end)
+        }).collect();
+
+        if self.training {
+            self.previous_inputs = Some(vec![q, k, v]);
+            self.previous_masks = Some(masks);
+            self.previous_statistics = None;
+            self.previous_sequence_length = sequence_length;
+            self.previous_tile_size = None;
+            self.previous_dropout_seed = None;
+        }
+
+        vec![Matrix::concat_rows(results)]
+    }
+
+    fn calculate_gradients(&mut self, previous_gradients: Vec<Matrix<f32>>) -> Vec<Matrix<f32>> {
+        if self.previous_inputs.is_none() {
+            println!("Scaled Dot Product Error - Previous inputs are none.");
+            return vec![];
+        }
+
+        // The path follows what the forward pass stored, in case `tile_size` has changed since.
+        if let (Some(_), Some(tile_size)) = (self.previous_statistics.as_ref(), self.previous_tile_size) {
+            return self.calculate_tiled_gradients(&previous_gradients[0], tile_size);
+        }
+
+        let masks: Vec<Matrix<f32>> = match self.previous_masks.clone() {
+            Some(masks) => masks,
+            None => {
+                println!("Scaled Dot Product Error - Previous masks are none.");
+                return vec![];
+            }
+        };
+
+        let q = self.previous_inputs.as_ref().unwrap()[0].clone();
+        let k = self.previous_inputs.as_ref().unwrap()[1].clone();
+        let v = self.previous_inputs.as_ref().unwrap()[2].clone();
+        let blocks: Vec<(Range<usize>, Range<usize>)> = ScaledDotProduct::sequence_blocks(q.rows, k.rows, self.previous_sequence_length);
+
+        let mut scaled_values: Vec<Matrix<f32>> = vec![];
+        let mut scaled_value_gradients: Vec<Matrix<f32>> = vec![];
+
+        for ((queries, keys), mask) in blocks.iter().zip(masks.iter()) {
+            scaled_values.push(self.probabilities(&q.slice_rows(queries.start, queries.end), &k.slice_rows(keys.start, keys.end), mask));
+            scaled_value_gradients.push(previous_gradients[0].slice_rows(queries.start, queries.end) * v.slice_rows(keys.start, keys.end).transpose());
+        }
+
+        let mut dropped_values: Vec<Matrix<f32>> = scaled_values.clone();
+
+        // Dropout is linear in its input, so its stored mask also reproduces the dropped probabilities.
+        if let Some(dropout) = self.dropout.as_mut() {
+            dropped_values = ScaledDotProduct::dropout_blocks(dropout, scaled_values.clone(), true);
+            scaled_value_gradients = ScaledDotProduct::dropout_blocks(dropout, scaled_value_gradients, true);
+        }
+
+        let mut q_gradients: Vec<Matrix<f32>> = vec![];
+        let mut k_gradients: Vec<Matrix<f32>> = vec![];
+        let mut v_gradients: Vec<Matrix<f32>> = vec![];
+
+        for (index, (queries, keys)) in blocks.iter().enumerate() {
+            let (scaled_value, scaled_value_gradient) = (&scaled_values[index], &scaled_value_gradients[index]);
+            let q_block: Matrix<f32> = q.slice_rows(queries.start, queries.end);
+            let k_block: Matrix<f32> = k.slice_rows(keys.start, keys.end);
+
+            // Masked scores have a softmax output of zero, so the Jacobian below already sends them no gradient.
+            let mut pre_scaled_gradients: Matrix<f32> = Matrix::new(scaled_value.rows, scaled_value.cols, 0.0);
+
+            // Softmax Jacobian per row: dS_j = P_j * (dP_j - sum_k(dP_k * P_k)).
+            for row in 0..scaled_value.rows {
+                let mut weighted_total: f32 = 0.0;
+
+                for col in 0..scaled_value.cols {
+                    weighted_total += scaled_value_gradient.get(row, col) * scaled_value.get(row, col);
+                }
+
+                for col in 0..scaled_value.cols {
+                    pre_scaled_gradients.set(row, col, scaled_value.get(row, col) * (scaled_value_gradient.get(row, col) - weighted_total));
+                }
+            }
+
+            v_gradients.push(dropped_values[index].transpose() * previous_gradients[0].slice_rows(queries.start, queries.end));
+            q_gradients.push(pre_scaled_gradients.clone() * k_block / (self.d_model as f32).sqrt());
+            k_gradients.push(pre_scaled_gradients.transpose() * q_block / (self.d_model as f32).sqrt());
+        }
+
+        vec![Matrix::concat_rows(q_gradients), Matrix::concat_rows(k_gradients), Matrix::concat_rows(v_gradients)]
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.training = training;
+
+        if let Some(dropout) = self.dropout.as_mut() {
+            dropout.set_training(training);
+        }
+    }
+
+    fn is_training(&self) -> bool {
+        self.training
+    }
+}
+
+
+pub const DEFAULT_ROTARY_BASE: f32 = 10000.0;
+
+// How token positions reach the model. Learned positions are added to the embeddings before the first block,
+// while rotary and ALiBi encodings are applied inside every attention layer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PositionEncoding {
+    Learned,
+    Rotary { base: f32 },
+    ALiBi
+}
+
+// Rotary position embedding (RoPE). Each pair of columns (2i, 2i + 1) in the row for position p is rotated
+// by the angle p * base^(-2i / d), so the dot product of a rotated query and key only depends on their
+// relative distance. The rotation is orthogonal, so its backward pass is the inverse rotation.
+pub struct RotaryEmbedding {
+    pub base: f32
+}
+
+impl RotaryEmbedding {
+    pub fn new(base: f32) -> RotaryEmbedding {
+        RotaryEmbedding {
+            base: base
+        }
+    }
+
+    // Rotates every row of `input`, where row r sits at position `start_position + r`.
+    pub fn rotate(&self, input: &Matrix<f32>, start_position: usize, inverse: bool) -> Matrix<f32> {
+        let mut result: Matrix<f32> = input.clone();
+        let direction: f32 = if inverse { -1.0 } else { 1.0 };
+
+        for row in 0..input.rows {
+            let position: f32 = (start_position + row) as f32;
+
+            for pair in 0..(input.cols / 2) {
+                let angle: f32 = direction * position * self.base.powf(-2.0 * pair as f32 / input.cols as f32);
+                let (sin, cos) = angle.sin_cos();
+
+                let first: f32 = input.get(row, 2 * pair);
+                let second: f32 = input.get(row, 2 * pair + 1);
+
+                result.set(row, 2 * pair, first * cos - second * sin);
+                result.set(row, 2 * pair + 1, first * sin + second * cos);
+            }
+        }
+
+        result
+    }
+
+    // As `rotate`, but with `sequence_length` set the rows hold a batch of consecutive sequences of that length,
+    // and each of them starts at `start_position`.
+    pub fn rotate_sequences(&self, input: &Matrix<f32>, start_position: usize, sequence_length: Option<usize>, inverse: bool) -> Matrix<f32> {
+        match sequence_length {
+            Some(length) if length < input.rows => {
+                let sequences: Vec<Matrix<f32>> = (0..input.rows).step_by(length).map(|start| {
+                    self.rotate(&input.slice_rows(start, (start + length).min(input.rows)), start_position, inverse)
+                }).collect();
+
+                Matrix::concat_rows(sequences)
+            },
+            _ => self.rotate(input, start_position, inverse)
+        }
+    }
+}
+
+
+// Keys and values from earlier positions of a sequence, so that generation only projects the new tokens.
+// One (positions x head_dimension) matrix is kept per key/value head, with keys stored after rotary rotation.
+// With a `capacity` (the attention window), the matrices are ring buffers holding only the most recent
+// `capacity` positions, so memory and per-token cost stay constant however long the sequence grows.
+pub struct KVCache {
+    pub keys: Vec<Matrix<f32>>,
+    pub values: Vec<Matrix<f32>>,
+    pub capacity: Option<usize>,
+
+    position_count: usize,
+    next_slot: usize
+}
+
+impl KVCache {
+    pub fn new(kv_head_count: usize, head_dimension: usize, capacity: Option<usize>) -> KVCache {
+        // A ring buffer with no slots could never hold the current position.
+        assert!(capacity != Some(0), "KV Cache Error - Capacity must be at least 1.");
+
+        KVCache {
+            keys: vec![Matrix::new(0, head_dimension, 0.0); kv_head_count],
+            values: vec![Matrix::new(0, head_dimension, 0.0); kv_head_count],
+            capacity: capacity,
+
+            position_count: 0,
+            next_slot: 0
+        }
+    }
+
+    // The number of positions seen so far, including any that have left the ring buffer.
+    pub fn len(&self) -> usize {
+        self.position_count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn append(&mut self, keys: Vec<Matrix<f32>>, values: Vec<Matrix<f32>>) {
+        let new_positions: usize = keys[0].rows;
+
+        for (index, (key, value)) in keys.into_iter().zip(values).enumerate() {
+            match self.capacity {
+                Some(capacity) => {
+                    let mut slot: usize = self.next_slot;
+
+                    for row in 0..key.rows {
+                        if self.keys[index].rows < capacity {
+                            self.keys[index] = Matrix::concat_rows(vec![self.keys[index].clone(), key.slice_rows(row, row + 1)]);
+                            self.values[index] = Matrix::concat_rows(vec![self.values[index].clone(), value.slice_rows(row, row + 1)]);
+                        } else {
+                            for col in 0..key.cols {
+                                self.keys[index].set(slot, col, key.get(row, col));
+                                self.values[index].set(slot, col, value.get(row, col));
+                            }
+                        }
+
+                        slot = (slot + 1) % capacity;
+                    }
+                },
+                None => {
+                    self.keys[index] = Matrix::concat_rows(vec![self.keys[index].clone(), key]);
+                    self.values[index] = Matrix::concat_rows(vec![self.values[index].clone(), value]);
+                }
+            }
+        }
+
+        if let Some(capacity) = self.capacity {
+            self.next_slot = (self.next_slot + new_positions) % capacity;
+        }
+
+        self.position_count += new_positions;
+    }
+
+    // The stored keys and values of every key/value head, oldest position first.
+    pub fn ordered(&self) -> (Vec<Matrix<f32>>, Vec<Matrix<f32>>) {
+        let unroll = |matrix: &Matrix<f32>| -> Matrix<f32> {
+            match self.capacity {
+                Some(capacity) if matrix.rows == capacity && self.next_slot > 0 => {
+                    Matrix::concat_rows(vec![matrix.slice_rows(self.next_slot, capacity), matrix.slice_rows(0, self.next_slot)])
+                },
+                _ => matrix.clone()
+            }
+        };
+
+        (self.keys.iter().map(unroll).collect(), self.values.iter().map(unroll).collect())
+    }
+}
+
+
+// Multi-head attention matching `MultiHeadedAttention` in the Python model. The inputs are either [x] for
+// self-attention or [q, k, v], and the projections are split column-wise into `head_count` heads.
+// When `rotary_embedding` is set, each head's queries and keys are rotated by position before attending.
+// Use `set_position_encoding` to switch between rotary, ALiBi and no in-attention position information.
+// With `kv_head_count` below `head_count`, consecutive groups of query heads share one key/value head
+// (grouped-query attention, or multi-query attention with a single key/value head).
+pub struct MultiHeadAttention {
+    pub d_model: usize,
+    pub head_count: usize,
+    pub kv_head_count: usize,
+
+    pub query_dense: Dense,
+    pub key_dense: Dense,
+    pub value_dense: Dense,
+    pub output_dense: Dense,
+
+    pub heads: Vec<ScaledDotProduct>,
+    pub rotary_embedding: Option<RotaryEmbedding>,
+
+    previous_input_count: usize,
+    previous_sequence_length: Option<usize>,
+    training: bool
+}
+
+impl MultiHeadAttention {
+    pub fn new(d_model: usize, head_count: usize, causal: bool, parameter_min: f32, parameter_max: f32) -> MultiHeadAttention {
+        MultiHeadAttention::with_kv_heads(d_model, head_count, head_count, causal, parameter_min, parameter_max)
+    }
+
+    pub fn with_kv_heads(d_model: usize, head_count: usize, kv_head_count: usize, causal: bool, parameter_min: f32, parameter_max: f32) -> MultiHeadAttention {
+        // Either mismatch would build a layer that fails on its first forward pass, so neither is allowed.
+        assert!(head_count > 0 && d_model.is_multiple_of(head_count), "Multi Head Attention Error - d_model ({}) is not divisible by the head count ({}).", d_model, head_count);
+        assert!(kv_head_count > 0 && head_count.is_multiple_of(kv_head_count), "Multi Head Attention Error - The head count ({}) is not divisible by the key/value head count ({}).", head_count, kv_head_count);
+
+        let head_dimension: usize = d_model / head_count;
+        let mut heads: Vec<ScaledDotProduct> = vec![];
+
+        for _ in 0..head_count {
+            let mut head: ScaledDotProduct = ScaledDotProduct::new(head_dimension);
+            head.causal = causal;
+            heads.push(head);
+        }
+
+        MultiHeadAttention {
+            d_model: d_model,
+            head_count: head_count,
+            kv_head_count: kv_head_count,
+
+            query_dense: Dense::new(d_model, d_model, parameter_min, parameter_max),
+            key_dense: Dense::new(kv_head_count * head_dimension, d_model, parameter_min, parameter_max),
+            value_dense: Dense::new(kv_head_count * head_dimension, d_model, parameter_min, parameter_max),
+            output_dense: Dense::new(d_model, d_model, parameter_min, parameter_max),
+
+            heads: heads,
+            rotary_embedding: None,
+
+            previous_input_count: 0,
+            previous_sequence_length: None,
+            training: true
+        }
+    }
+
+    fn head_dimension(&self) -> usize {
+        self.d_model / self.head_count
+    }
+
+    // Number of query heads sharing each key/value head.
+    fn group_size(&self) -> usize {
+        self.head_count / self.kv_head_count
+    }
+
+    pub fn new_kv_cache(&self) -> KVCache {
+        KVCache::new(self.kv_head_count, self.head_dimension(), self.heads[0].window)
+    }
+
+    // Splits projected keys and values into their key/value heads, rotating keys that start at `start_position`.
+    fn split_kv_heads(&self, k: &Matrix<f32>, v: &Matrix<f32>, start_position: usize) -> (Vec<Matrix<f32>>, Vec<Matrix<f32>>) {
+        let head_dimension: usize = self.head_dimension();
+        let mut keys: Vec<Matrix<f32>> = vec![];
+        let mut values: Vec<Matrix<f32>> = vec![];
+
+        for index in 0..self.kv_head_count {
+            let start: usize = index * head_dimension;
+            let mut key: Matrix<f32> = k.slice_cols(start, start + head_dimension);
+
+            if let Some(rotary_embedding) = self.rotary_embedding.as_ref() {
+                key = rotary_embedding.rotate_sequences(&key, start_position, self.heads[0].sequence_length, false);
+            }
+
+            keys.push(key);
+            values.push(v.slice_cols(start, start + head_dimension));
+        }
+
+        (keys, values)
+    }
+
+    // Runs every query head against its shared key/value head and projects the concatenated results.
+    fn attend(&mut self, q: &Matrix<f32>, query_start_position: usize, keys: &[Matrix<f32>], values: &[Matrix<f32>]) -> Vec<Matrix<f32>> {
+        let head_dimension: usize = self.head_dimension();
+        let group_size: usize = self.group_size();
+        let sequence_length: Option<usize> = self.heads[0].sequence_length;
+        let mut head_results: Vec<Matrix<f32>> = vec![];
+
+        for (index, head) in self.heads.iter_mut().enumerate() {
+            let start: usize = index * head_dimension;
+            let mut head_q: Matrix<f32> = q.slice_cols(start, start + head_dimension);
+
+            if let Some(rotary_embedding) = self.rotary_embedding.as_ref() {
+                head_q = rotary_embedding.rotate_sequences(&head_q, query_start_position, sequence_length, false);
+            }
+
+            head_results.push(head.forward(vec![head_q, keys[index / group_size].clone(), values[index / group_size].clone()])[0].clone());
+        }
+
+        self.output_dense.forward(vec![Matrix::concat_cols(head_results)])
+    }
+
+    // Inference-only self-attention over `input`, the rows following those already in `cache`. The new keys and
+    // values are appended to the cache, so feeding a sequence in pieces matches a single causal forward pass.
+    // A windowed cache only holds the last `window` positions, which is all the new queries can see.
+    // The layer runs in eval mode and on a single sequence for the call, so attention dropout is never applied.
+    pub fn forward_cached(&mut self, input: Matrix<f32>, cache: &mut KVCache) -> Matrix<f32> {
+        let was_training: bool = self.training;
+        let sequence_length: Option<usize> = self.heads[0].sequence_length;
+        self.set_training(false);
+        self.set_sequence_length(None);
+
+        let q: Matrix<f32> = self.query_dense.forward(vec![input.clone()])[0].clone();
+        let k: Matrix<f32> = self.key_dense.forward(vec![input.clone()])[0].clone();
+        let v: Matrix<f32> = self.value_dense.forward(vec![input])[0].clone();
+
+        let start_position: usize = cache.len();
+        let (new_keys, new_values) = self.split_kv_heads(&k, &v, start_position);
+        let (past_keys, past_values) = cache.ordered();
+
+        let keys: Vec<Matrix<f32>> = past_keys.into_iter().zip(new_keys.iter()).map(|(past, new)| Matrix::concat_rows(vec![past, new.clone()])).collect();
+        let values: Vec<Matrix<f32>> = past_values.into_iter().zip(new_values.iter()).map(|(past, new)| Matrix::concat_rows(vec![past, new.clone()])).collect();
+
+        cache.append(new_keys, new_values);
+
+        let output: Matrix<f32> = self.attend(&q, start_position, &keys, &values)[0].clone();
+
+        self.set_training(was_training);
+        self.set_sequence_length(sequence_length);
+        output
+    }
+
+    pub fn set_position_encoding(&mut self, position_encoding: PositionEncoding) {
+        self.rotary_embedding = match position_encoding {
+            PositionEncoding::Rotary { base } => Some(RotaryEmbedding::new(base)),
+            _ => None
+        };
+
+        let slopes: Vec<f32> = alibi_slopes(self.head_count);
+
+        for (index, head) in self.heads.iter_mut().enumerate() {
+            head.alibi_slope = if position_encoding == PositionEncoding::ALiBi { Some(slopes[index]) } else { None };
+        }
+    }
+
+    // Applies dropout to every head's attention probabilities, each head with its own seed. A rate of zero removes it.
+    pub fn set_attention_dropout(&mut self, rate: f32, seed: u64) {
+        let mut seeds: StdRng = StdRng::seed_from_u64(seed);
+
+        for head in self.heads.iter_mut() {
+            head.dropout = if rate > 0.0 { Some(Dropout::new(rate, seeds.next_u64())) } else { None };
+
+            if let Some(dropout) = head.dropout.as_mut() {
+                dropout.set_training(head.training);
+            }
+        }
+    }
+
+    // Switches every head to tiled attention with (tile_size x tile_size) blocks, or back to the full score matrix.
+    pub fn set_tile_size(&mut self, tile_size: Option<usize>) {
+        for head in self.heads.iter_mut() {
+            head.tile_size = tile_size;
+        }
+    }
+
+    // Limits every head to the `window` most recent positions. Caches made by `new_kv_cache` afterwards keep only that many.
+    pub fn set_window(&mut self, window: Option<usize>) {
+        // A query always sees at least itself, so a window of zero has no meaning. No window is None.
+        assert!(window != Some(0), "Multi Head Attention Error - The attention window must be at least 1.");
+
+        for head in self.heads.iter_mut() {
+            head.window = window;
+        }
+    }
+
+    // Treats the rows of the next forward passes as a batch of consecutive sequences of `sequence_length` positions
+    // each, which attend only within themselves and whose rotary positions restart at zero. None is one sequence.
+    // Each forward pass keeps the setting it ran with for its backward pass, so it can be changed in between.
+    pub fn set_sequence_length(&mut self, sequence_length: Option<usize>) {
+        for head in self.heads.iter_mut() {
+            head.sequence_length = sequence_length;
+        }
+    }
+
+    // Marks padded key positions for the next forward pass, e.g. the tail of a shorter sequence in a batch.
+    pub fn set_key_padding(&mut self, key_padding: Option<Vec<bool>>) {
+        for head in self.heads.iter_mut() {
+            head.key_padding = key_padding.clone();
+        }
+    }
+}
+
+impl Layer for MultiHeadAttention {
+    fn forward(&mut self, inputs: Vec<Matrix<f32>>) -> Vec<Matrix<f32>> {
+        let (q_input, k_input, v_input) = if inputs.len() == 1 {
+            (inputs[0].clone(), inputs[0].clone(), inputs[0].clone())
+        } else {
+            (inputs[0].clone(), inputs[1].clone(), inputs[2].clone())
+        };
+
+        if self.training {
+            self.previous_input_count = inputs.len();
+            self.previous_sequence_length = self.heads[0].sequence_length;
+        }
+
+        let q: Matrix<f32> = self.query_dense.forward(vec![q_input])[0].clone();
+        let k: Matrix<f32> = self.key_dense.forward(vec![k_input])[0].clone();
+        let v: Matrix<f32> = self.value_dense.forward(vec![v_input])[0].clone();
+
+        let query_start_position: usize = k.rows.saturating_sub(q.rows);
+        let (keys, values) = self.split_kv_heads(&k, &v, 0);
+
+        self.attend(&q, query_start_position, &keys, &values)
+    }
+
+    fn calculate_gradients(&mut self, previous_gradients: Vec<Matrix<f32>>) -> Vec<Matrix<f32>> {
+        if self.previous_input_count == 0 {
+            println!("Multi Head Attention Error - Previous inputs are none.");
+            return vec![];
+        }
+
+        let concat_gradients: Matrix<f32> = self.output_dense.calculate_gradients(previous_gradients)[0].clone();
+
+        let head_dimension: usize = self.head_dimension();
+        let group_size: usize = self.group_size();
+        let sequence_length: Option<usize> = self.previous_sequence_length;
+        let mut q_gradients: Vec<Matrix<f32>> = vec![];
+        let mut k_gradients: Vec<Matrix<f32>> = vec![];
+        let mut v_gradients: Vec<Matrix<f32>> = vec![];
+
+        for (index, head) in self.heads.iter_mut().enumerate() {
+            let start: usize = index * head_dimension;
+            let head_gradients: Vec<Matrix<f32>> = head.calculate_gradients(vec![concat_gradients.slice_cols(start, start + head_dimension)]);
+
+            let mut head_q_gradients: Matrix<f32> = head_gradients[0].clone();
+
+            if let Some(rotary_embedding) = self.rotary_embedding.as_ref() {
+                let query_start_position: usize = head_gradients[1].rows.saturating_sub(head_q_gradients.rows);
+                head_q_gradients = rotary_embedding.rotate_sequences(&head_q_gradients, query_start_position, sequence_length, true);
+            }
+
+            q_gradients.push(head_q_gradients);
+
+            // Every query head in a group adds onto the gradient of the key/value head it shares.
+            let kv_index: usize = index / group_size;
+
+            if kv_index == k_gradients.len() {
+                k_gradients.push(head_gradients[1].clone());
+                v_gradients.push(head_gradients[2].clone());
+            } else {
+                k_gradients[kv_index] = k_gradients[kv_index].clone() + head_gradients[1].clone();
+                v_gradients[kv_index] = v_gradients[kv_index].clone() + head_gradients[2].clone();
+            }
+        }
+
+        if let Some(rotary_embedding) = self.rotary_embedding.as_ref() {
+            for k_gradient in k_gradients.iter_mut() {
+                *k_gradient = rotary_embedding.rotate_sequences(k_gradient, 0, sequence_length, true);
+            }
+        }
+
+        let q_input_gradients: Matrix<f32> = self.query_dense.calculate_gradients(vec![Matrix::concat_cols(q_gradients)])[0].clone();
+        let k_input_gradients: Matrix<f32> = self.key_dense.calculate_gradients(vec![Matrix::concat_cols(k_gradients)])[0].clone();
+        let v_input_gradients: Matrix<f32> = self.value_dense.calculate_gradients(vec![Matrix::concat_cols(v_gradients)])[0].clone();
+
+        if self.previous_input_count == 1 {
+            return vec![q_input_gradients + k_input_gradients + v_input_gradients];
+        }
+
+        vec![q_input_gradients, k_input_gradients, v_input_gradients]
+    }
+
+    fn parameters(&self) -> Vec<(String, &Parameter)> {
+        let mut parameters: Vec<(String, &Parameter)> = prefix_parameters("query_dense", self.query_dense.parameters());
+        parameters.extend(prefix_parameters("key_dense", self.key_dense.parameters()));
+        parameters.extend(prefix_parameters("value_dense", self.value_dense.parameters()));
+        parameters.extend(prefix_parameters("output_dense", self.output_dense.parameters()));
+        parameters
+    }
+
+    fn parameters_mut(&mut self) -> Vec<(String, &mut Parameter)> {
+        let mut parameters: Vec<(String, &mut Parameter)> = prefix_parameters("query_dense", self.query_dense.parameters_mut());
+        parameters.extend(prefix_parameters("key_dense", self.key_dense.parameters_mut()));
+        parameters.extend(prefix_parameters("value_dense", self.value_dense.parameters_mut()));
+        parameters.extend(prefix_parameters("output_dense", self.output_dense.parameters_mut()));
+        parameters
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.training = training;
+
+        self.query_dense.set_training(training);
+        self.key_dense.set_training(training);
+        self.value_dense.set_training(training);
+        self.output_dense.set_training(training);
+
+        for head in self.heads.iter_mut() {
+            head.set_training(training);
+        }
+    }
+
+    fn is_training(&self) -> bool {
+        self.training
+    }
+}
+
+
+// Pre-norm transformer block matching `Decoder` in the Python model:
+// h = x + attention(norm_1(x)), output = h + ffn(norm_2(h)).
+// Optional residual dropout is applied to each branch before it is added back: h = x + dropout_1(attention(...)).
+pub struct Decoder {
+    pub norm_1: Box<dyn Layer>,
+    pub attention: MultiHeadAttention,
+    pub norm_2: Box<dyn Layer>,
+    pub ffn: Box<dyn Layer>,
+
+    pub residual_dropout_1: Option<Dropout>,
+    pub residual_dropout_2: Option<Dropout>,
+
+    training: bool
+}
+
+impl Decoder {
+    pub fn new(d_model: usize, head_count: usize, ffn_inner_size: usize, normalization: Normalization, feed_forward: FeedForward, parameter_min: f32, parameter_max: f32) -> Decoder {
+        Decoder::with_sublayers(
+            normalization_layer(normalization, d_model),
+            MultiHeadAttention::new(d_model, head_count, true, parameter_min, parameter_max),
+            normalization_layer(normalization, d_model),
+            feed_forward_layer(feed_forward, d_model, ffn_inner_size, parameter_min, parameter_max)
+        )
+    }
+
+    // Builds a block with every option from `config`, including the key/value head count and dropout rates.
+    pub fn from_config(config: &LLMConfig, dropout_seed: u64) -> Decoder {
+        let mut attention: MultiHeadAttention = MultiHeadAttention::with_kv_heads(config.d_model, config.head_count, config.kv_head_count, true, config.parameter_min, config.parameter_max);
+        attention.set_position_encoding(config.position_encoding);
+        attention.set_window(config.attention_window);
+        attention.set_tile_size(config.attention_tile_size);
+
+        let mut decoder: Decoder = Decoder::with_sublayers(
+            normalization_layer(config.normalization, config.d_model),
+            attention,
+            normalization_layer(config.normalization, config.d_model),
+            feed_forward_layer(config.feed_forward, config.d_model, config.ffn_inner_size, config.parameter_min, config.parameter_max)
+        );
+
+        decoder.set_dropout(config.attention_dropout, config.residual_dropout, dropout_seed);
+        decoder
+    }
+
+    pub fn with_sublayers(norm_1: Box<dyn Layer>, attention: MultiHeadAttention, norm_2: Box<dyn Layer>, ffn: Box<dyn Layer>) -> Decoder {
+        Decoder {
+            norm_1: norm_1,
+            attention: attention,
+            norm_2: norm_2,
+            ffn: ffn,
+
+            residual_dropout_1: None,
+            residual_dropout_2: None,
+
+            training: true
+        }
+    }
+
+    // Inference-only forward pass over the positions following those in `cache`. The block runs in eval mode for
+    // the call, so no dropout is applied, and residual dropout is skipped altogether.
+    pub fn forward_cached(&mut self, input: Matrix<f32>, cache: &mut KVCache) -> Matrix<f32> {
+        let was_training: bool = self.training;
+        self.set_training(false);
+
+        let normalized_1: Matrix<f32> = self.norm_1.forward(vec![input.clone()])[0].clone();
+        let hidden: Matrix<f32> = input + self.attention.forward_cached(normalized_1, cache);
+
+        let normalized_2: Vec<Matrix<f32>> = self.norm_2.forward(vec![hidden.clone()]);
+        let output: Matrix<f32> = hidden + self.ffn.forward(normalized_2)[0].clone();
+
+        self.set_training(was_training);
+        output
+    }
+
+    // See `MultiHeadAttention::set_sequence_length`. Every other sublayer works on each row independently.
+    pub fn set_sequence_length(&mut self, sequence_length: Option<usize>) {
+        self.attention.set_sequence_length(sequence_length);
+    }
+
+    // Sets dropout on the attention probabilities and on both residual branches. A rate of zero disables it.
+    pub fn set_dropout(&mut self, attention_rate: f32, residual_rate: f32, seed: u64) {
+        let mut seeds: StdRng = StdRng::seed_from_u64(seed);
+
+        self.attention.set_attention_dropout(attention_rate, seeds.next_u64());
+
+        self.residual_dropout_1 = if residual_rate > 0.0 { Some(Dropout::new(residual_rate, seeds.next_u64())) } else { None };
+        self.residual_dropout_2 = if residual_rate > 0.0 { Some(Dropout::new(residual_rate, seeds.next_u64())) } else { None };
+
+        let training: bool = self.training;
+        self.set_training(training);
+    }
+}
+
+impl Layer for Decoder {
+    fn forward(&mut self, inputs: Vec<Matrix<f32>>) -> Vec<Matrix<f32>> {
+        let input: Matrix<f32> = inputs[0].clone();
+
+        let normalized_1: Vec<Matrix<f32>> = self.norm_1.forward(inputs);
+        let mut attention_output: Vec<Matrix<f32>> = self.attention.forward(normalized_1);
+
+        if let Some(dropout) = self.residual_dropout_1.as_mut() {
+            attention_output = dropout.forward(attention_output);
+        }
+
+        let hidden: Matrix<f32> = input + attention_output[0].clone();
+
+        let normalized_2: Vec<Matrix<f32>> = self.norm_2.forward(vec![hidden.clone()]);
+        let mut ffn_output: Vec<Matrix<f32>> = self.ffn.forward(normalized_2);
+
+        if let Some(dropout) = self.residual_dropout_2.as_mut() {
+            ffn_output = dropout.forward(ffn_output);
+        }
+
+        vec![hidden + ffn_output[0].clone()]
+    }
+
+    fn calculate_gradients(&mut self, previous_gradients: Vec<Matrix<f32>>) -> Vec<Matrix<f32>> {
+        // The output gradient reaches the hidden state through both the skip connection and the FFN branch.
+        let mut ffn_output_gradients: Vec<Matrix<f32>> = previous_gradients.clone();
+
+        if let Some(dropout) = self.residual_dropout_2.as_mut() {
+            ffn_output_gradients = dropout.calculate_gradients(ffn_output_gradients);
+        }
+
+        let ffn_gradients: Vec<Matrix<f32>> = self.ffn.calculate_gradients(ffn_output_gradients);
+        let norm_2_gradients: Vec<Matrix<f32>> = self.norm_2.calculate_gradients(ffn_gradients);
+
+        if norm_2_gradients.is_empty() {
+            println!("Decoder Error - FFN branch returned no gradients.");
+            return vec![];
+        }
+
+        let hidden_gradients: Matrix<f32> = previous_gradients[0].clone() + norm_2_gradients[0].clone();
+
+        let mut attention_output_gradients: Vec<Matrix<f32>> = vec![hidden_gradients.clone()];
+
+        if let Some(dropout) = self.residual_dropout_1.as_mut() {
+            attention_output_gradients = dropout.calculate_gradients(attention_output_gradients);
+        }
+
+        let attention_gradients: Vec<Matrix<f32>> = self.attention.calculate_gradients(attention_output_gradients);
+        let norm_1_gradients: Vec<Matrix<f32>> = self.norm_1.calculate_gradients(attention_gradients);
+
+        if norm_1_gradients.is_empty() {
+            println!("Decoder Error - Attention branch returned no gradients.");
+            return vec![];
+        }
+
+        vec![hidden_gradients + norm_1_gradients[0].clone()]
+    }
+
+    fn parameters(&self) -> Vec<(String, &Parameter)> {
+        let mut parameters: Vec<(String, &Parameter)> = prefix_parameters("norm_1", self.norm_1.parameters());
+        parameters.extend(prefix_parameters("attention", self.attention.parameters()));
+        parameters.extend(prefix_parameters("norm_2", self.norm_2.parameters()));
+        parameters.extend(prefix_parameters("ffn", self.ffn.parameters()));
+        parameters
+    }
+
+    fn parameters_mut(&mut self) -> Vec<(String, &mut Parameter)> {
+        let mut parameters: Vec<(String, &mut Parameter)> = prefix_parameters("norm_1", self.norm_1.parameters_mut());
+        parameters.extend(prefix_parameters("attention", self.attention.parameters_mut()));
+        parameters.extend(prefix_parameters("norm_2", self.norm_2.parameters_mut()));
+        parameters.extend(prefix_parameters("ffn", self.ffn.parameters_mut()));
+        parameters
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.training = training;
+
+        self.norm_1.set_training(training);
+        self.attention.set_training(training);
+        self.norm_2.set_training(training);
+        self.ffn.set_training(training);
+
+        if let Some(dropout) = self.residual_dropout_1.as_mut() {
+            dropout.set_training(training);
+        }
+
+        if let Some(dropout) = self.residual_dropout_2.as_mut() {
+            dropout.set_training(training);
+        }
+    }
+
+    fn is_training(&self) -> bool {
+        self.training
+    }
+}
+
+
+pub struct LLMConfig {
+    pub vocabulary_size: usize,
+    pub d_model: usize,
+    pub head_count: usize,
+    pub kv_head_count: usize,
+    pub ffn_inner_size: usize,
+    pub decoder_count: usize,
+    pub max_positions: usize,
+    pub position_encoding: PositionEncoding,
+    pub normalization: Normalization,
+    pub feed_forward: FeedForward,
+
+    pub attention_dropout: f32,
+    pub residual_dropout: f32,
+    pub dropout_seed: u64,
+    pub attention_window: Option<usize>,
+    pub attention_tile_size: Option<usize>,
+
+    pub parameter_min: f32,
+    pub parameter_max: f32
+}
+
+impl LLMConfig {
+    pub fn new(vocabulary_size: usize, d_model: usize, head_count: usize, ffn_inner_size: usize, decoder_count: usize, max_positions: usize) -> LLMConfig {
+        LLMConfig {
+            vocabulary_size: vocabulary_size,
+            d_model: d_model,
+            head_count: head_count,
+            kv_head_count: head_count,
+            ffn_inner_size: ffn_inner_size,
+            decoder_count: decoder_count,
+            max_positions: max_positions,
+            position_encoding: PositionEncoding::Learned,
+            normalization: Normalization::LayerNorm,
+            feed_forward: FeedForward::Standard(Activation::GELU),
+
+            attention_dropout: 0.0,
+            residual_dropout: 0.0,
+            dropout_seed: 0,
+            attention_window: None,
+            attention_tile_size: None,
+
+            parameter_min: -0.05,
+            parameter_max: 0.05
+        }
+    }
+
+    // The number of values in every parameter of the model this config builds, without building it. The count is a
+    // u128 so that it cannot overflow for any sizes, e.g. those read from a corrupt checkpoint.
+    pub fn parameter_count(&self) -> u128 {
+        let (vocabulary_size, d_model, max_positions) = (self.vocabulary_size as u128, self.d_model as u128, self.max_positions as u128);
+        let (inner_size, decoder_count) = (self.ffn_inner_size as u128, self.decoder_count as u128);
+        let kv_size: u128 = (self.kv_head_count * (self.d_model / self.head_count.max(1))) as u128;
+
+        let dense = |nodes: u128, input_size: u128| input_size * nodes + nodes;
+        let norm: u128 = match self.normalization {
+            Normalization::LayerNorm => 2 * d_model,
+            Normalization::RMSNorm => d_model
+        };
+
+        let attention: u128 = 2 * dense(d_model, d_model) + 2 * dense(kv_size, d_model);
+        let feed_forward: u128 = match self.feed_forward {
+            FeedForward::Standard(_) => dense(inner_size, d_model) + dense(d_model, inner_size),
+            FeedForward::SwiGLU => 2 * dense(inner_size, d_model) + dense(d_model, inner_size)
+        };
+        let positions: u128 = if self.position_encoding == PositionEncoding::Learned { max_positions * d_model } else { 0 };
+
+        // The output projection is tied to the token embedding, so it adds nothing.
+        vocabulary_size * d_model + positions + decoder_count * (2 * norm + attention + feed_forward) + norm
+    }
+}
+
+
+// Decoder-only language model matching `LLM` in the Python model: token (and learned positional) embeddings,
+// `decoder_count` decoder blocks, a final LayerNorm, and an output projection tied to the token embedding weights.
+// The input is a (sequence_length x 1) column of token indices and the output is (sequence_length x vocabulary_size).
+// `forward_batch` runs several sequences of the same length at once as (batch_size * sequence_length) rows.
+pub struct LLM {
+    pub config: LLMConfig,
+
+    pub token_embedding: Embedding,
+    pub positional_embedding: Option<PositionalEmbedding>,
+    pub decoders: Vec<Decoder>,
+    pub final_norm: Box<dyn Layer>,
+    pub output_projection: TiedProjection,
+
+    training: bool
+}
+
+impl LLM {
+    pub fn new(vocabulary_size: usize, d_model: usize, head_count: usize, ffn_inner_size: usize, decoder_count: usize, max_positions: usize) -> LLM {
+        LLM::from_config(LLMConfig::new(vocabulary_size, d_model, head_count, ffn_inner_size, decoder_count, max_positions))
+    }
+
+    pub fn from_config(config: LLMConfig) -> LLM {
+        let mut decoders: Vec<Decoder> = vec![];
+        let mut dropout_seeds: StdRng = StdRng::seed_from_u64(config.dropout_seed);
+
+        for _ in 0..config.decoder_count {
+            decoders.push(Decoder::from_config(&config, dropout_seeds.next_u64()));
+        }
+
+        let token_embedding: Embedding = Embedding::new(config.vocabulary_size, config.d_model, config.parameter_min, config.parameter_max);
+        let output_projection: TiedProjection = TiedProjection::new(token_embedding.weights.share());
+
+        LLM {
+            token_embedding: token_embedding,
+            positional_embedding: match config.position_encoding {
+                PositionEncoding::Learned => Some(PositionalEmbedding::new(config.max_positions, config.d_model, config.parameter_min, config.parameter_max)),
+                _ => None
+            },
+            decoders: decoders,
+            final_norm: normalization_layer(config.normalization, config.d_model),
+            output_projection: output_projection,
+
+            config: config,
+
+            training: true
+        }
+    }
+
+    pub fn forward_tokens(&mut self, tokens: &[usize]) -> Matrix<f32> {
+        self.set_sequence_length(None);
+        self.forward(vec![Embedding::indices_to_matrix(tokens)])[0].clone()
+    }
+
+    // Runs every sequence through the model in a single pass, with the rows of each sequence following those of the
+    // one before. Sequences do not attend to each other and each starts at position zero, so row block i of the
+    // (batch_size * sequence_length x vocabulary_size) output matches `forward_tokens(&sequences[i])`. A following
+    // `calculate_gradients` covers the whole batch. The split only applies to this call, and later `forward` calls
+    // treat their rows as one sequence again.
+    pub fn forward_batch(&mut self, sequences: &[Vec<usize>]) -> Matrix<f32> {
+        let sequence_length: usize = sequences.first().map_or(0, |sequence| sequence.len());
+        let row_count: usize = sequences.iter().map(|sequence| sequence.len()).sum();
+
+        if sequence_length == 0 || sequences.iter().any(|sequence| sequence.len() != sequence_length) {
+            println!("LLM Error - A batch needs non-empty sequences of equal length.");
+            return Matrix::new(row_count, self.config.vocabulary_size, 0.0);
+        }
+
+        self.set_sequence_length(Some(sequence_length));
+        let output: Vec<Matrix<f32>> = self.forward(vec![Embedding::indices_to_matrix(&sequences.concat())]);
+        self.set_sequence_length(None);
+
+        match output.into_iter().next() {
+            Some(output) => output,
+            None => Matrix::new(row_count, self.config.vocabulary_size, 0.0)
+        }
+    }
+
+    // Sets how the rows of later `forward` calls split into sequences. None treats them as one sequence.
+    pub fn set_sequence_length(&mut self, sequence_length: Option<usize>) {
+        if let Some(positional_embedding) = self.positional_embedding.as_mut() {
+            positional_embedding.sequence_length = sequence_length;
+        }
+
+        for decoder in self.decoders.iter_mut() {
+            decoder.set_sequence_length(sequence_length);
+        }
+    }
+
+    // One empty cache per decoder block, for use with `forward_tokens_cached`.
+    pub fn new_kv_caches(&self) -> Vec<KVCache> {
+        self.decoders.iter().map(|decoder| decoder.attention.new_kv_cache()).collect()
+    }
+
+    // Inference-only forward pass over `tokens`, which continue the sequence already held in `caches`.
+    // Returns the logits for the new tokens only. The model runs in eval mode for the call, so dropout is never
+    // applied and the values cached for `calculate_gradients` are left untouched.
+    pub fn forward_tokens_cached(&mut self, tokens: &[usize], caches: &mut [KVCache]) -> Matrix<f32> {
+        let was_training: bool = self.training;
+        self.set_training(false);
+
+        let start_position: usize = caches[0].len();
+        let embedded: Vec<Matrix<f32>> = self.token_embedding.forward(vec![Embedding::indices_to_matrix(tokens)]);
+
+        if embedded.is_empty() {
+            self.set_training(was_training);
+            return Matrix::new(tokens.len(), self.config.vocabulary_size, 0.0);
+        }
+
+        let mut hidden: Matrix<f32> = embedded[0].clone();
+
+        if let Some(positional_embedding) = self.positional_embedding.as_ref() {
+            hidden = positional_embedding.add_positions(&hidden, start_position);
+        }
+
+        for (decoder, cache) in self.decoders.iter_mut().zip(caches.iter_mut()) {
+            hidden = decoder.forward_cached(hidden, cache);
+        }
+
+        hidden = self.final_norm.forward(vec![hidden])[0].clone();
+        let logits: Matrix<f32> = self.output_projection.forward(vec![hidden])[0].clone();
+
+        self.set_training(was_training);
+        logits
+    }
+}
+
+impl Layer for LLM {
+    fn forward(&mut self, inputs: Vec<Matrix<f32>>) -> Vec<Matrix<f32>> {
+        let mut hidden: Vec<Matrix<f32>> = self.token_embedding.forward(inputs);
+
+        if hidden.is_empty() {
+            return vec![];
+        }
+
+        if let Some(positional_embedding) = self.positional_embedding.as_mut() {
+            hidden = positional_embedding.forward(hidden);
+        }
+
+        for decoder in self.decoders.iter_mut() {
+            hidden = decoder.forward(hidden);
+        }
+
+        hidden = self.final_norm.forward(hidden);
+        self.output_projection.forward(hidden)
+    }
+
+    fn calculate_gradients(&mut self, previous_gradients: Vec<Matrix<f32>>) -> Vec<Matrix<f32>> {
+        // Both the output projection and the embedding lookup add onto the shared token embedding gradient.
+        let mut gradients: Vec<Matrix<f32>> = self.output_projection.calculate_gradients(previous_gradients);
+        gradients = self.final_norm.calculate_gradients(gradients);
+
+        for decoder in self.decoders.iter_mut().rev() {
+            gradients = decoder.calculate_gradients(gradients);
+        }
+
+        if let Some(positional_embedding) = self.positional_embedding.as_mut() {
+            gradients = positional_embedding.calculate_gradients(gradients);
+        }
+
+        self.token_embedding.calculate_gradients(gradients)
+    }
+
+    fn parameters(&self) -> Vec<(String, &Parameter)> {
+        let mut parameters: Vec<(String, &Parameter)> = prefix_parameters("token_embedding", self.token_embedding.parameters());
+
+        if let Some(positional_embedding) = self.positional_embedding.as_ref() {
+            parameters.extend(prefix_parameters("positional_embedding", positional_embedding.parameters()));
+        }
+
+        for (index, decoder) in self.decoders.iter().enumerate() {
+            parameters.extend(prefix_parameters(&format!("decoder_{}", index), decoder.parameters()));
+        }
+
+        parameters.extend(prefix_parameters("final_norm", self.final_norm.parameters()));
+        parameters
+    }
+
+    fn parameters_mut(&mut self) -> Vec<(String, &mut Parameter)> {
+        let mut parameters: Vec<(String, &mut Parameter)> = prefix_parameters("token_embedding", self.token_embedding.parameters_mut());
+
+        if let Some(positional_embedding) = self.positional_embedding.as_mut() {
+            parameters.extend(prefix_parameters("positional_embedding", positional_embedding.parameters_mut()));
+        }
+
+        for (index, decoder) in self.decoders.iter_mut().enumerate() {
+            parameters.extend(prefix_parameters(&format!("decoder_{}", index), decoder.parameters_mut()));
+        }
+
+        parameters.extend(prefix_parameters("final_norm", self.final_norm.parameters_mut()));
+        parameters
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.training = training;
+
+        self.token_embedding.set_training(training);
+
+        if let Some(positional_embedding) = self.positional_embedding.as_mut() {
+            positional_embedding.set_training(training);
+        }
+
+        for decoder in self.decoders.iter_mut() {
+            decoder.set_training(training);
+        }
+
+        self.final_norm.set_training(training);
+        self.output_projection.set_training(training);
+    }
+
+    fn is_training(&self) -> bool {
+        self.training
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stale_key_padding_is_rejected() {
+        let mut attention: ScaledDotProduct = ScaledDotProduct::new(4);
+        attention.key_padding = Some(vec![false, true]);
+
+        let output: Matrix<f32> = attention.forward(vec![generate_parameter(3, 4, -1.0, 1.0), generate_parameter(5, 4, -1.0, 1.0), generate_parameter(5, 4, -1.0, 1.0)])[0].clone();
+
+        assert_eq!((output.rows, output.cols), (3, 4));
+        assert!(output.data.iter().all(|value| *value == 0.0));
+    }
+
+    #[test]
+    fn attention_applies_causal_and_padding_masks() {
+        let mut attention: ScaledDotProduct = ScaledDotProduct::new(4);
+        attention.causal = true;
+        attention.key_padding = Some(vec![false, true, false, false, false]);
+
+        // Three queries aligned to the end of five keys: query i sees keys up to i + 2, except the padded key 1.
+        let mask: Matrix<f32> = attention.mask_tile(0..3, 0..5, 3, 5, None, None);
+        let visible: [[bool; 5]; 3] = [[true, false, true, false, false], [true, false, true, true, false], [true, false, true, true, true]];
+
+        for (row, row_visible) in visible.iter().enumerate() {
+            for (col, &is_visible) in row_visible.iter().enumerate() {
+                assert_eq!(mask.get(row, col), if is_visible { 0.0 } else { MASK_VALUE }, "mask ({}, {})", row, col);
+            }
+        }
+
+        // With the identity as the values, the output is the attention probabilities themselves.
+        let mut identity: Matrix<f32> = Matrix::new(5, 5, 0.0);
+
+        for index in 0..5 {
+            identity.set(index, index, 1.0);
+        }
+
+        let probabilities: Matrix<f32> = attention.forward(vec![generate_parameter(3, 4, -1.0, 1.0), generate_parameter(5, 4, -1.0, 1.0), identity])[0].clone();
+
+        for (row, row_visible) in visible.iter().enumerate() {
+            let total: f32 = (0..5).map(|col| probabilities.get(row, col)).sum();
+            assert!((total - 1.0).abs() < 1e-6);
+
+            for (col, &is_visible) in row_visible.iter().enumerate() {
+                assert!(is_visible || probabilities.get(row, col) == 0.0, "probability ({}, {})", row, col);
+            }
+        }
+    }
+
+    #[test]
+    fn embedding_rejects_an_index_outside_the_vocabulary() {
+        let mut embedding: Embedding = Embedding::new(4, 3, -1.0, 1.0);
+
+        assert!(embedding.forward(vec![Embedding::indices_to_matrix(&[1, 4, 2])]).is_empty());
+        assert!(embedding.previous_indices.is_none());
+        assert_eq!(embedding.forward(vec![Embedding::indices_to_matrix(&[1, 3, 2])])[0].rows, 3);
+
+        // The model stops at the embedding instead of running on zero rows.
+        let mut model: LLM = LLM::from_config(LLMConfig::new(4, 8, 2, 16, 1, 8));
+        assert!(model.forward(vec![Embedding::indices_to_matrix(&[0, 5])]).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "must be in [0, 1)")]
+    fn dropout_rejects_a_rate_of_one() {
+        Dropout::new(1.0, 0);
+    }
+
+    #[test]
+    fn dropout_drops_about_rate_and_scales_the_survivors() {
+        let mut dropout: Dropout = Dropout::new(0.25, 7);
+        let output: Matrix<f32> = dropout.forward(vec![Matrix::new(100, 100, 2.0)])[0].clone();
+
+        let dropped: usize = output.data.iter().filter(|value| **value == 0.0).count();
+        assert!((dropped as f32 / 10000.0 - 0.25).abs() < 0.02, "{} of 10000 values dropped", dropped);
+        assert!(output.data.iter().all(|value| *value == 0.0 || (value - 2.0 / 0.75).abs() < 1e-6));
+    }
+
+    #[test]
+    fn dropout_gradients_reuse_the_forward_mask() {
+        let mut dropout: Dropout = Dropout::new(0.5, 3);
+        let output: Matrix<f32> = dropout.forward(vec![Matrix::new(8, 8, 1.0)])[0].clone();
+
+        // The input was all ones, so the output is the mask itself: zero, or the 1 / (1 - rate) scale.
+        let output_gradients: Matrix<f32> = generate_parameter(8, 8, -1.0, 1.0);
+        let expected: Matrix<f32> = output_gradients.element_mult(output);
+
+        // Running backward twice reuses the same stored mask rather than drawing a new one.
+        for _ in 0..2 {
+            assert_eq!(dropout.calculate_gradients(vec![output_gradients.clone()])[0].data, expected.data);
+        }
+    }
+
+    #[test]
+    fn dropout_passes_inputs_through_in_eval_mode() {
+        let mut dropout: Dropout = Dropout::new(0.5, 3);
+        dropout.eval();
+
+        let input: Matrix<f32> = generate_parameter(6, 6, -1.0, 1.0);
+        assert_eq!(dropout.forward(vec![input.clone()])[0].data, input.data);
+    }
+
+    #[test]
+    fn dropout_seed_fixes_the_mask() {
+        let input: Matrix<f32> = Matrix::new(16, 16, 1.0);
+
+        let first: Matrix<f32> = Dropout::new(0.5, 11).forward(vec![input.clone()])[0].clone();
+        let second: Matrix<f32> = Dropout::new(0.5, 11).forward(vec![input.clone()])[0].clone();
+        let other_seed: Matrix<f32> = Dropout::new(0.5, 12).forward(vec![input])[0].clone();
+
+        assert_eq!(first.data, second.data);
+        assert_ne!(first.data, other_seed.data);
+    }
+
+    #[test]
+    #[should_panic(expected = "not divisible by the key/value head count")]
+    fn attention_rejects_uneven_key_value_groups() {
+        MultiHeadAttention::with_kv_heads(8, 4, 3, true, -0.5, 0.5);
+    }
+
+    #[test]
+    #[should_panic(expected = "attention window must be at least 1")]
+    fn attention_rejects_a_zero_window() {
+        MultiHeadAttention::new(8, 2, true, -0.5, 0.5).set_window(Some(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "Capacity must be at least 1")]
+    fn kv_cache_rejects_a_zero_capacity() {
+        KVCache::new(2, 4, Some(0));
+    }
+
+    #[test]
+    fn cached_forward_ignores_dropout() {
+        let mut config: LLMConfig = LLMConfig::new(7, 8, 2, 16, 2, 8);
+        config.attention_dropout = 0.5;
+        config.residual_dropout = 0.5;
+
+        let mut model: LLM = LLM::from_config(config);
+        let tokens: [usize; 5] = [1, 4, 2, 6, 0];
+
+        let first: Matrix<f32> = model.forward_tokens_cached(&tokens, &mut model.new_kv_caches());
+        let second: Matrix<f32> = model.forward_tokens_cached(&tokens, &mut model.new_kv_caches());
+
+        assert!(model.is_training());
+        assert_eq!(first.data, second.data);
+
+        model.eval();
+        let full: Matrix<f32> = model.forward_tokens(&tokens);
+
+        for (cached, expected) in first.data.iter().zip(full.data.iter()) {
+            assert!((cached - expected).abs() < 1e-5);
+        }
+    }
+
+    fn max_difference(a: &Matrix<f32>, b: &Matrix<f32>) -> f32 {
+        a.data.iter().zip(b.data.iter()).map(|(x, y)| (x - y).abs()).fold(0.0, f32::max)
+    }
+
+    // Feeds the tokens through the cache in uneven chunks and compares every chunk with the same rows of one
+    // full forward pass.
+    fn assert_cached_generation_matches(config: LLMConfig, token_count: usize) {
+        seed_parameters(0);
+
+        let window: Option<usize> = config.attention_window;
+        let mut model: LLM = LLM::from_config(config);
+        model.eval();
+
+        let tokens: Vec<usize> = (0..token_count).map(|index| (index * 7 + 3) % model.config.vocabulary_size).collect();
+        let full: Matrix<f32> = model.forward_tokens(&tokens);
+
+        let mut caches: Vec<KVCache> = model.new_kv_caches();
+        let mut position: usize = 0;
+
+        for chunk in [1, 3, 2, 6, 1, 4, 5].iter().cycle() {
+            if position == token_count {
+                break;
+            }
+
+            let end: usize = (position + chunk).min(token_count);
+            let output: Matrix<f32> = model.forward_tokens_cached(&tokens[position..end], &mut caches);
+
+            assert!(max_difference(&full.slice_rows(position, end), &output) < 1e-5, "{:?} with {} key/value heads: cached rows {}..{} differ from the full pass.", model.config.position_encoding, model.config.kv_head_count, position, end);
+
+            if let Some(window) = window {
+                assert!(caches.iter().all(|cache| cache.keys.iter().all(|keys| keys.rows <= window)));
+            }
+
+            position = end;
+        }
+    }
+
+    #[test]
+    fn cached_generation_matches_full_forward() {
+        for position_encoding in [PositionEncoding::Learned, PositionEncoding::Rotary { base: 10000.0 }, PositionEncoding::ALiBi] {
+            // Multi-head, grouped-query and multi-query attention.
+            for kv_head_count in [4, 2, 1] {
+                let mut config: LLMConfig = LLMConfig::new(9, 8, 4, 12, 2, 24);
+                config.kv_head_count = kv_head_count;
+                config.position_encoding = position_encoding;
+
+                assert_cached_generation_matches(config, 20);
+            }
+
+            let mut tiled: LLMConfig = LLMConfig::new(9, 8, 4, 12, 2, 24);
+            tiled.kv_head_count = 2;
+            tiled.position_encoding = position_encoding;
+            tiled.attention_tile_size = Some(3);
+
+            assert_cached_generation_matches(tiled, 20);
+
+            // The window is shorter than the sequence, so the cache has to drop old keys as it goes.
+            let mut windowed: LLMConfig = LLMConfig::new(9, 8, 4, 12, 2, 24);
+            windowed.kv_head_count = 2;
+            windowed.position_encoding = position_encoding;
+            windowed.attention_window = Some(5);
+
+            assert_cached_generation_matches(windowed, 20);
+        }
+    }
+
+    #[test]
+    fn tiled_window_matches_dense_window() {
+        let inputs: Matrix<f32> = generate_parameter(30, 8, -2.0, 2.0);
+        let output_gradients: Matrix<f32> = generate_parameter(30, 8, -1.0, 1.0);
+
+        let mut dense: ScaledDotProduct = ScaledDotProduct::new(8);
+        dense.causal = true;
+        dense.window = Some(6);
+
+        let mut tiled: ScaledDotProduct = ScaledDotProduct::new(8);
+        tiled.causal = true;
+        tiled.window = Some(6);
+        tiled.tile_size = Some(4);
+
+        let dense_output: Matrix<f32> = dense.forward(vec![inputs.clone(), inputs.clone(), inputs.clone()])[0].clone();
+        let tiled_output: Matrix<f32> = tiled.forward(vec![inputs.clone(), inputs.clone(), inputs.clone()])[0].clone();
+        assert!(max_difference(&dense_output, &tiled_output) < 1e-5);
+
+        let dense_gradients: Vec<Matrix<f32>> = dense.calculate_gradients(vec![output_gradients.clone()]);
+        let tiled_gradients: Vec<Matrix<f32>> = tiled.calculate_gradients(vec![output_gradients]);
+
+        for (dense_gradient, tiled_gradient) in dense_gradients.iter().zip(tiled_gradients.iter()) {
+            assert!(max_difference(dense_gradient, tiled_gradient) < 1e-5);
+        }
+    }
+
+    #[test]
+    fn backward_follows_the_path_forward_took() {
+        let inputs: Matrix<f32> = generate_parameter(7, 4, -1.0, 1.0);
+        let output_gradients: Matrix<f32> = generate_parameter(7, 4, -1.0, 1.0);
+
+        // A tiled forward switched to untiled before its backward, and the other way round.
+        for (forward_tile_size, backward_tile_size) in [(Some(3), None), (None, Some(3))] {
+            let mut reference: ScaledDotProduct = ScaledDotProduct::new(4);
+            reference.causal = true;
+
+            let mut switched: ScaledDotProduct = ScaledDotProduct::new(4);
+            switched.causal = true;
+            switched.tile_size = forward_tile_size;
+
+            reference.forward(vec![inputs.clone(), inputs.clone(), inputs.clone()]);
+            switched.forward(vec![inputs.clone(), inputs.clone(), inputs.clone()]);
+            switched.tile_size = backward_tile_size;
+
+            let expected: Vec<Matrix<f32>> = reference.calculate_gradients(vec![output_gradients.clone()]);
+            let gradients: Vec<Matrix<f32>> = switched.calculate_gradients(vec![output_gradients.clone()]);
+
+            assert_eq!(gradients.len(), 3);
+
+            for (expected, gradient) in expected.iter().zip(gradients.iter()) {
+                assert!(max_difference(expected, gradient) < 1e-5);
+            }
+        }
+    }
+
+    fn tiled_dropout_head() -> ScaledDotProduct {
+        let mut head: ScaledDotProduct = ScaledDotProduct::new(4);
+        head.causal = true;
+        head.tile_size = Some(3);
+        head.dropout = Some(Dropout::new(0.3, 5));
+        head
+    }
+
+    #[test]
+    fn tiled_dropout_replays_its_masks_in_the_backward_pass() {
+        seed_parameters(1);
+
+        let inputs: Vec<Matrix<f32>> = (0..3).map(|_| generate_parameter(7, 4, -1.0, 1.0)).collect();
+        let output_gradients: Matrix<f32> = generate_parameter(7, 4, -1.0, 1.0);
+        let loss = |inputs: Vec<Matrix<f32>>| -> f64 {
+            let output: Matrix<f32> = tiled_dropout_head().forward(inputs)[0].clone();
+            output.data.iter().zip(output_gradients.data.iter()).map(|(value, gradient)| *value as f64 * *gradient as f64).sum()
+        };
+
+        let mut head: ScaledDotProduct = tiled_dropout_head();
+        let output: Matrix<f32> = head.forward(inputs.clone())[0].clone();
+
+        // Dropout changes the output, and still no full score matrix or mask is kept.
+        let mut undropped: ScaledDotProduct = tiled_dropout_head();
+        undropped.dropout = None;
+        assert!(max_difference(&output, &undropped.forward(inputs.clone())[0]) > 1e-3);
+        assert!(head.previous_masks.is_none());
+
+        // Every forward pass of a fresh head draws the same masks, so finite differences see the dropout of the backward pass.
+        let gradients: Vec<Matrix<f32>> = head.calculate_gradients(vec![output_gradients.clone()]);
+        let step: f32 = 1e-2;
+
+        for (input_index, gradient) in gradients.iter().enumerate() {
+            for element in 0..gradient.data.len() {
+                let (mut plus, mut minus) = (inputs.clone(), inputs.clone());
+                plus[input_index].data[element] += step;
+                minus[input_index].data[element] -= step;
+
+                let expected: f64 = (loss(plus) - loss(minus)) / (2.0 * step as f64);
+                assert!((gradient.data[element] as f64 - expected).abs() < 1e-3, "input {} element {}: expected {}, got {}", input_index, element, expected, gradient.data[element]);
+            }
+        }
+    }
+
+    #[test]
+    fn fully_masked_rows_output_zero_in_both_paths() {
+        let inputs: Matrix<f32> = generate_parameter(6, 4, -1.0, 1.0);
+        let output_gradients: Matrix<f32> = generate_parameter(6, 4, -1.0, 1.0);
+
+        // The first query may only see the first key, which is padding.
+        let mut dense: ScaledDotProduct = ScaledDotProduct::new(4);
+        dense.causal = true;
+        dense.key_padding = Some(vec![true, false, false, false, false, false]);
+
+        let mut tiled: ScaledDotProduct = ScaledDotProduct::new(4);
+        tiled.causal = true;
+        tiled.key_padding = Some(vec![true, false, false, false, false, false]);
+        tiled.tile_size = Some(2);
+
+        let dense_output: Matrix<f32> = dense.forward(vec![inputs.clone(), inputs.clone(), inputs.clone()])[0].clone();
+        let tiled_output: Matrix<f32> = tiled.forward(vec![inputs.clone(), inputs.clone(), inputs.clone()])[0].clone();
+
+        assert!(dense_output.slice_rows(0, 1).data.iter().all(|value| *value == 0.0));
+        assert!(max_difference(&dense_output, &tiled_output) < 1e-5);
+
+        let dense_gradients: Vec<Matrix<f32>> = dense.calculate_gradients(vec![output_gradients.clone()]);
+        let tiled_gradients: Vec<Matrix<f32>> = tiled.calculate_gradients(vec![output_gradients]);
+
+        for (dense_gradient, tiled_gradient) in dense_gradients.iter().zip(tiled_gradients.iter()) {
+            assert!(dense_gradient.data.iter().all(|value| value.is_finite()));
+            assert!(max_difference(dense_gradient, tiled_gradient) < 1e-5);
+        }
+    }
+
+    fn parameter_gradients(model: &LLM) -> Vec<Matrix<f32>> {
+        model.parameters().iter().map(|(_, parameter)| parameter.borrow().gradient.clone().unwrap()).collect()
+    }
+
+    // A batch must give each sequence the outputs it gets on its own, and the sum of their parameter gradients.
+    fn assert_batch_matches_sequences(config: LLMConfig) {
+        seed_parameters(0);
+
+        let mut model: LLM = LLM::from_config(config);
+        let sequences: Vec<Vec<usize>> = vec![vec![1, 4, 2, 6, 0, 3], vec![5, 5, 1, 8, 2, 7], vec![0, 2, 4, 6, 8, 1]];
+        let output_gradients: Matrix<f32> = generate_parameter(18, model.config.vocabulary_size, -1.0, 1.0);
+
+        model.zero_grad();
+        let mut expected_outputs: Vec<Matrix<f32>> = vec![];
+
+        for (index, sequence) in sequences.iter().enumerate() {
+            expected_outputs.push(model.forward_tokens(sequence));
+            model.calculate_gradients(vec![output_gradients.slice_rows(6 * index, 6 * index + 6)]);
+        }
+
+        let expected_gradients: Vec<Matrix<f32>> = parameter_gradients(&model);
+
+        model.zero_grad();
+        let output: Matrix<f32> = model.forward_batch(&sequences);
+        model.calculate_gradients(vec![output_gradients]);
+
+        assert!(max_difference(&Matrix::concat_rows(expected_outputs), &output) < 1e-5, "{:?}: batched outputs differ.", model.config.position_encoding);
+
+        for (expected, batched) in expected_gradients.iter().zip(parameter_gradients(&model).iter()) {
+            assert!(max_difference(expected, batched) < 1e-4, "{:?}: batched gradients differ.", model.config.position_encoding);
+        }
+    }
+
+    #[test]
+    fn batched_forward_matches_separate_sequences() {
+        for position_encoding in [PositionEncoding::Learned, PositionEncoding::Rotary { base: 10000.0 }, PositionEncoding::ALiBi] {
+            let mut config: LLMConfig = LLMConfig::new(9, 8, 4, 12, 2, 6);
+            config.kv_head_count = 2;
+            config.position_encoding = position_encoding;
+
+            assert_batch_matches_sequences(config);
+
+            // Tiles of four rows straddle the sequences, and the window is shorter than them.
+            let mut tiled: LLMConfig = LLMConfig::new(9, 8, 4, 12, 2, 6);
+            tiled.position_encoding = position_encoding;
+            tiled.attention_tile_size = Some(4);
+            tiled.attention_window = Some(4);
+
+            assert_batch_matches_sequences(tiled);
+        }
+    }
+
+    #[test]
+    fn batched_attention_attends_within_each_sequence() {
+        let inputs: Matrix<f32> = generate_parameter(12, 8, -2.0, 2.0);
+
+        let mut batched: ScaledDotProduct = ScaledDotProduct::new(8);
+        batched.causal = true;
+        batched.sequence_length = Some(4);
+
+        let output: Matrix<f32> = batched.forward(vec![inputs.clone(), inputs.clone(), inputs.clone()])[0].clone();
+
+        // Only one (4 x 4) mask per sequence is built and kept, rather than a (12 x 12) one.
+        let masks: &Vec<Matrix<f32>> = batched.previous_masks.as_ref().unwrap();
+        assert!(masks.len() == 3 && masks.iter().all(|mask| (mask.rows, mask.cols) == (4, 4)));
+
+        for start in [0, 4, 8] {
+            let mut single: ScaledDotProduct = ScaledDotProduct::new(8);
+            single.causal = true;
+
+            let sequence: Matrix<f32> = inputs.slice_rows(start, start + 4);
+            let expected: Matrix<f32> = single.forward(vec![sequence.clone(), sequence.clone(), sequence])[0].clone();
+
+            assert!(max_difference(&expected, &output.slice_rows(start, start + 4)) < 1e-6);
+        }
+    }
+
+    #[test]
+    fn batch_split_only_applies_to_its_call() {
+        let mut model: LLM = LLM::new(9, 8, 2, 12, 1, 6);
+        let tokens: Vec<usize> = vec![1, 4, 2, 6, 0, 3];
+        let expected: Matrix<f32> = model.forward_tokens(&tokens);
+
+        model.forward_batch(&[vec![1, 4, 2], vec![6, 0, 3]]);
+        let output: Matrix<f32> = model.forward(vec![Embedding::indices_to_matrix(&tokens)])[0].clone();
+
+        assert!(max_difference(&expected, &output) < 1e-6);
+    }
+
+    #[test]
+    fn config_parameter_count_matches_the_built_model() {
+        let mut grouped: LLMConfig = LLMConfig::new(9, 8, 4, 12, 2, 6);
+        grouped.kv_head_count = 2;
+        grouped.normalization = Normalization::RMSNorm;
+        grouped.feed_forward = FeedForward::SwiGLU;
+
+        let mut rotary: LLMConfig = LLMConfig::new(11, 12, 3, 20, 3, 16);
+        rotary.position_encoding = PositionEncoding::Rotary { base: DEFAULT_ROTARY_BASE };
+
+        for config in [LLMConfig::new(7, 8, 2, 16, 1, 8), grouped, rotary] {
+            assert_eq!(config.parameter_count(), LLM::from_config(config).parameter_count() as u128);
+        }
+    }
+
+    #[test]
+    fn uneven_batch_is_rejected() {
+        let mut model: LLM = LLM::new(9, 8, 2, 12, 1, 6);
+        let output: Matrix<f32> = model.forward_batch(&[vec![1, 2, 3], vec![4, 5]]);
+
+        assert_eq!((output.rows, output.cols), (5, 9));
+        assert!(output.data.iter().all(|value| *value == 0.0));
+    }
+}